@@ -0,0 +1,396 @@
+use secp256k1::{
+    Secp256k1, ContextFlag,
+    key::{SecretKey, ZERO_KEY},
+    pedersen::Commitment,
+};
+use rand::thread_rng;
+
+use crate::{offset_sum, rand_blinding, Hash, Error, Result};
+use crate::consensus::{self, ChainParams};
+use crate::kernel::{KernelFeatures, TxKernel};
+use crate::ser::{self, Writeable, Readable, Writer, Reader};
+use crate::transaction::{Output, OutputFeatures, Transaction};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub height: u64,
+    // Hash of the previous block's header.
+    pub previous: Hash,
+    pub timestamp: u64,
+    // Work of this block and of the chain up to and including it.
+    pub difficulty: u64,
+    pub total_difficulty: u64,
+    // Sum of the kernel offsets of all blocks up to and including this one.
+    pub total_kernel_offset: SecretKey,
+    // Roots and sizes of the chain's MMRs once this block is applied.
+    pub output_root: Hash,
+    pub rangeproof_root: Hash,
+    pub kernel_root: Hash,
+    pub output_mmr_size: u64,
+    pub kernel_mmr_size: u64
+}
+
+impl BlockHeader {
+    pub fn hash(&self) -> Result<Hash> {
+        ser::hash(self)
+    }
+}
+
+impl Writeable for BlockHeader {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        writer.write_u64(self.height);
+        self.previous.write(writer)?;
+        writer.write_u64(self.timestamp);
+        writer.write_u64(self.difficulty);
+        writer.write_u64(self.total_difficulty);
+        self.total_kernel_offset.write(writer)?;
+        self.output_root.write(writer)?;
+        self.rangeproof_root.write(writer)?;
+        self.kernel_root.write(writer)?;
+        writer.write_u64(self.output_mmr_size);
+        writer.write_u64(self.kernel_mmr_size);
+        Ok(())
+    }
+}
+
+impl Readable for BlockHeader {
+    fn read(reader: &mut Reader) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            height: reader.read_u64()?,
+            previous: Hash::read(reader)?,
+            timestamp: reader.read_u64()?,
+            difficulty: reader.read_u64()?,
+            total_difficulty: reader.read_u64()?,
+            total_kernel_offset: reader.read_kernel_offset()?,
+            output_root: Hash::read(reader)?,
+            rangeproof_root: Hash::read(reader)?,
+            kernel_root: Hash::read(reader)?,
+            output_mmr_size: reader.read_u64()?,
+            kernel_mmr_size: reader.read_u64()?
+        })
+    }
+}
+
+// All transactions of a block merged together, coinbase included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockBody {
+    pub inputs: Vec<Commitment>,
+    pub outputs: Vec<Output>,
+    pub kernels: Vec<TxKernel>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub body: BlockBody
+}
+
+impl Block {
+    // Blocks are built with empty roots, which depend on the whole chain and
+    // are filled in by `ChainState`.
+
+    // First block of the chain, with nothing but the coinbase.
+    pub fn genesis(
+        secp: &Secp256k1,
+        params: &ChainParams,
+        coinbase_blinding: &SecretKey,
+        timestamp: u64
+    ) -> Result<Block> {
+        Block::build(secp, params, None, vec![], coinbase_blinding, timestamp)
+    }
+
+    // Block on top of `prev` including `txs` and a coinbase output worth the
+    // reward plus all fees, blinded with `coinbase_blinding`.
+    pub fn new(
+        secp: &Secp256k1,
+        params: &ChainParams,
+        prev: &BlockHeader,
+        txs: Vec<Transaction>,
+        coinbase_blinding: &SecretKey,
+        timestamp: u64
+    ) -> Result<Block> {
+        Block::build(secp, params, Some(prev), txs, coinbase_blinding, timestamp)
+    }
+
+    fn build(
+        secp: &Secp256k1,
+        params: &ChainParams,
+        prev: Option<&BlockHeader>,
+        txs: Vec<Transaction>,
+        coinbase_blinding: &SecretKey,
+        timestamp: u64
+    ) -> Result<Block> {
+        let mut fees = 0u64;
+        for tx in &txs {
+            fees = fees.checked_add(tx.fee()?).ok_or(Error::FeeOverflow)?;
+        }
+        let mut inputs = vec![];
+        let mut outputs = vec![];
+        let mut kernels = vec![];
+        let mut offsets = vec![];
+        if !txs.is_empty() {
+            let tx = Transaction::aggregate(secp, txs)?;
+            inputs = tx.inputs;
+            outputs = tx.outputs;
+            kernels = tx.kernels;
+            offsets.push(tx.kernel_offset);
+        }
+
+        let (height, previous, prev_difficulty) = match prev {
+            Some(prev) => {
+                offsets.push(prev.total_kernel_offset);
+                (prev.height + 1, prev.hash()?, prev.total_difficulty)
+            },
+            None => (0, [0; 32], 0)
+        };
+
+        // Coinbase: output - (reward + fees) * H == coinbase_blinding * G
+        let value = params.reward(height).checked_add(fees).ok_or(Error::FeeOverflow)?;
+        outputs.push(Output::coinbase(secp, value, coinbase_blinding)?);
+        kernels.push(TxKernel::new(secp, KernelFeatures::Coinbase, 0, 0, coinbase_blinding)?);
+        outputs.sort_by_key(|o| o.commit);
+        kernels.sort_by_key(|k| k.hash());
+
+        let body = BlockBody { inputs, outputs, kernels };
+        let header = BlockHeader {
+            height,
+            previous,
+            timestamp,
+            difficulty: consensus::BLOCK_DIFFICULTY,
+            total_difficulty: prev_difficulty + consensus::BLOCK_DIFFICULTY,
+            total_kernel_offset: offset_sum(secp, offsets, vec![])?,
+            output_root: [0; 32],
+            rangeproof_root: [0; 32],
+            kernel_root: [0; 32],
+            output_mmr_size: 0,
+            kernel_mmr_size: 0
+        };
+        Ok(Block { header, body })
+    }
+
+    // Kernel offset of this block alone.
+    pub fn kernel_offset(
+        &self,
+        secp: &Secp256k1,
+        prev: Option<&BlockHeader>
+    ) -> Result<SecretKey> {
+        // Equal totals, like an empty block's, cancel out to ZERO_KEY.
+        let prev_offset = prev.map(|p| p.total_kernel_offset).unwrap_or(ZERO_KEY);
+        offset_sum(secp, vec![self.header.total_kernel_offset], vec![prev_offset])
+    }
+
+    // Check the header against the previous one (`None` for genesis), range
+    // proofs, kernel signatures, the coinbase and the block sum:
+    // outputs - inputs - reward * H == kernel excesses + offset * G
+    // Transaction fees cancel out, as they're spent by the coinbase.
+    pub fn validate(
+        &self,
+        secp: &Secp256k1,
+        params: &ChainParams,
+        prev: Option<&BlockHeader>
+    ) -> Result<()> {
+        let header = &self.header;
+        let body = &self.body;
+        if header.difficulty != consensus::BLOCK_DIFFICULTY {
+            return Err(Error::InvalidBlockHeader);
+        }
+        match prev {
+            Some(prev) => {
+                if header.height != prev.height + 1
+                    || header.previous != prev.hash()?
+                    || header.timestamp < prev.timestamp
+                    || Some(header.total_difficulty)
+                        != prev.total_difficulty.checked_add(header.difficulty)
+                {
+                    return Err(Error::InvalidBlockHeader);
+                }
+            },
+            None => {
+                if header.height != 0
+                    || header.previous != [0; 32]
+                    || header.total_difficulty != header.difficulty
+                {
+                    return Err(Error::InvalidBlockHeader);
+                }
+            }
+        }
+
+        for output in &body.outputs {
+            output.verify(secp)?;
+        }
+        for kernel in &body.kernels {
+            if kernel.lock_height() > header.height {
+                return Err(Error::LockedKernel);
+            }
+            kernel.verify(secp)?;
+        }
+
+        let reward = params.reward(header.height);
+        self.validate_coinbase(secp, reward)?;
+
+        let positive: Vec<Commitment> = body.outputs.iter().map(|o| o.commit).collect();
+        let mut negative = body.inputs.clone();
+        if reward != 0 {
+            negative.push(secp.commit_value(reward)?);
+        }
+        negative.extend(body.kernels.iter().map(|k| k.excess));
+        let offset = self.kernel_offset(secp, prev)?;
+        if offset != ZERO_KEY {
+            negative.push(secp.commit(0, offset)?);
+        }
+        if secp.verify_commit_sum(positive, negative) {
+            Ok(())
+        } else {
+            Err(Error::UnbalancedBlock)
+        }
+    }
+
+    // The coinbase mints exactly the reward and collects all fees:
+    // coinbase outputs - fees * H == reward * H + coinbase kernel excesses
+    fn validate_coinbase(&self, secp: &Secp256k1, reward: u64) -> Result<()> {
+        let body = &self.body;
+        let positive: Vec<Commitment> = body.outputs.iter()
+            .filter(|o| o.is_coinbase())
+            .map(|o| o.commit)
+            .collect();
+        let mut negative: Vec<Commitment> = body.kernels.iter()
+            .filter(|k| k.features == KernelFeatures::Coinbase)
+            .map(|k| k.excess)
+            .collect();
+        if positive.is_empty() || negative.is_empty() {
+            return Err(Error::InvalidCoinbase);
+        }
+
+        let fees = body.kernels.iter()
+            .try_fold(0u64, |sum, k| sum.checked_add(k.fee))
+            .ok_or(Error::InvalidCoinbase)?;
+        let minted = reward.checked_add(fees).ok_or(Error::InvalidCoinbase)?;
+        if minted != 0 {
+            negative.push(secp.commit_value(minted)?);
+        }
+        if secp.verify_commit_sum(positive, negative) {
+            Ok(())
+        } else {
+            Err(Error::InvalidCoinbase)
+        }
+    }
+}
+
+// Transfer of `amount` out of an input worth `value`.
+#[cfg(test)]
+pub(crate) fn transfer(
+    secp: &Secp256k1,
+    value: u64,
+    blinding: &SecretKey,
+    amount: u64,
+    fee: u64
+) -> Transaction {
+    use crate::{Sender, Receiver};
+
+    let sender = Sender::initiate(secp, value, blinding, amount, fee).unwrap();
+    let receiver = Receiver::respond(secp, &sender.message).unwrap();
+    sender.finalize(secp, &receiver.response).unwrap()
+}
+
+#[test]
+fn test_block() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+    let params = ChainParams {
+        initial_reward: 1_000_000,
+        halving_interval: 1,
+        ..ChainParams::default()
+    };
+
+    let genesis_blinding = rand_blinding(&secp);
+    let genesis = Block::genesis(&secp, &params, &genesis_blinding, 1).unwrap();
+    assert_eq!(genesis.validate(&secp, &params, None), Ok(()));
+    assert_eq!(genesis.header.total_kernel_offset, ZERO_KEY);
+
+    // Spend the genesis coinbase in two transactions of the next block.
+    let tx1 = transfer(&secp, 1_000_000, &genesis_blinding, 1_000, 3);
+    let tx2 = transfer(&secp, 5_000, &rand_blinding(&secp), 2_000, 4);
+    let txs = vec![tx1, tx2];
+    let block = Block::new(&secp, &params, &genesis.header, txs, &rand_blinding(&secp), 2).unwrap();
+    let prev = Some(&genesis.header);
+    assert_eq!(block.header.height, 1);
+    assert_eq!(block.header.previous, genesis.header.hash().unwrap());
+    assert_eq!(block.body.kernels.len(), 3);
+    assert_eq!(block.body.outputs.iter().filter(|o| o.is_coinbase()).count(), 1);
+    assert_eq!(block.validate(&secp, &params, prev), Ok(()));
+
+    // The reward was halved at height 1.
+    let other_params = ChainParams { halving_interval: 2, ..params.clone() };
+    assert_eq!(block.validate(&secp, &other_params, prev), Err(Error::InvalidCoinbase));
+
+    // Empty block.
+    let prev = &block.header;
+    let empty = Block::new(&secp, &params, prev, vec![], &rand_blinding(&secp), 3).unwrap();
+    assert_eq!(empty.validate(&secp, &params, Some(prev)), Ok(()));
+    assert_eq!(empty.kernel_offset(&secp, Some(prev)), Ok(ZERO_KEY));
+}
+
+#[test]
+fn test_block_rejects() {
+    use crate::{Sender, Receiver};
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+    let params = ChainParams::default();
+
+    let genesis = Block::genesis(&secp, &params, &rand_blinding(&secp), 1).unwrap();
+    let tx = transfer(&secp, 5_000, &rand_blinding(&secp), 2_000, 4);
+    let block = Block::new(&secp, &params, &genesis.header, vec![tx], &rand_blinding(&secp), 2)
+        .unwrap();
+    let prev = Some(&genesis.header);
+
+    // Wrong previous header.
+    assert_eq!(block.validate(&secp, &params, None), Err(Error::InvalidBlockHeader));
+    let mut other = block.clone();
+    other.header.previous = [1; 32];
+    assert_eq!(other.validate(&secp, &params, prev), Err(Error::InvalidBlockHeader));
+    let mut other = block.clone();
+    other.header.height = 2;
+    assert_eq!(other.validate(&secp, &params, prev), Err(Error::InvalidBlockHeader));
+    let mut other = block.clone();
+    other.header.total_difficulty += 1;
+    assert_eq!(other.validate(&secp, &params, prev), Err(Error::InvalidBlockHeader));
+
+    // Difficulty other than the fixed one, even with a consistent total.
+    let mut other = block.clone();
+    other.header.difficulty = 5;
+    other.header.total_difficulty = 6;
+    assert_eq!(other.validate(&secp, &params, prev), Err(Error::InvalidBlockHeader));
+
+    // Wrong total offset.
+    let mut other = block.clone();
+    other.header.total_kernel_offset = rand_blinding(&secp);
+    assert_eq!(other.validate(&secp, &params, prev), Err(Error::UnbalancedBlock));
+
+    // Kernel locked above the block height.
+    let sender = Sender::initiate(&secp, 5_000, &rand_blinding(&secp), 2_000, 4)
+        .unwrap()
+        .with_lock_height(2);
+    let receiver = Receiver::respond(&secp, &sender.message).unwrap();
+    let tx = sender.finalize(&secp, &receiver.response).unwrap();
+    let (prev_0, prev_1) = (&genesis.header, &block.header);
+    let txs = vec![tx.clone()];
+    let other = Block::new(&secp, &params, prev_0, txs, &rand_blinding(&secp), 2).unwrap();
+    assert_eq!(other.validate(&secp, &params, Some(prev_0)), Err(Error::LockedKernel));
+    let other = Block::new(&secp, &params, prev_1, vec![tx], &rand_blinding(&secp), 3).unwrap();
+    assert_eq!(other.validate(&secp, &params, Some(prev_1)), Ok(()));
+
+    // Coinbase minting more than the reward.
+    let blinding = rand_blinding(&secp);
+    let mut other = genesis.clone();
+    other.body.outputs = vec![Output::coinbase(&secp, params.reward(0) + 1, &blinding).unwrap()];
+    other.body.kernels = vec![
+        TxKernel::new(&secp, KernelFeatures::Coinbase, 0, 0, &blinding).unwrap()
+    ];
+    assert_eq!(other.validate(&secp, &params, None), Err(Error::InvalidCoinbase));
+
+    // Coinbase not marked as such.
+    let mut other = genesis.clone();
+    other.body.outputs[0].features = OutputFeatures::Plain;
+    assert_eq!(other.validate(&secp, &params, None), Err(Error::InvalidCoinbase));
+}
@@ -0,0 +1,794 @@
+use std::collections::{HashMap, HashSet};
+
+use secp256k1::{
+    Secp256k1, ContextFlag,
+    key::{SecretKey, ZERO_KEY},
+    pedersen::Commitment,
+};
+use rand::thread_rng;
+
+use crate::{rand_blinding, Hash, Error, Result};
+use crate::block::{Block, BlockBody, BlockHeader};
+use crate::consensus::ChainParams;
+use crate::kernel::TxKernel;
+use crate::pmmr::{MerkleProof, Pmmr};
+use crate::transaction::{Output, OutputFeatures, Transaction};
+use crate::utxo::UtxoSet;
+
+// Leaf data of the MMRs.
+fn output_leaf(output: &Output) -> Vec<u8> {
+    utxo_leaf(output.features, &output.commit)
+}
+
+fn utxo_leaf(features: OutputFeatures, commit: &Commitment) -> Vec<u8> {
+    let mut data = vec![features.as_u8()];
+    data.extend_from_slice(&commit.0[..]);
+    data
+}
+
+fn rangeproof_leaf(output: &Output) -> Vec<u8> {
+    output.proof.proof[..output.proof.plen].to_vec()
+}
+
+fn kernel_leaf(kernel: &TxKernel) -> Vec<u8> {
+    kernel.hash().to_vec()
+}
+
+// Check a proof that `output` is in the output MMR as of `header`.
+pub fn verify_output_proof(header: &BlockHeader, output: &Output, proof: &MerkleProof) -> bool {
+    proof.mmr_size == header.output_mmr_size
+        && proof.verify(&header.output_root, &output_leaf(output))
+}
+
+// What `ChainState::validate_full` found inconsistent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainComponent {
+    // Kernel history doesn't match the kernel MMR of the tip.
+    KernelRoot,
+    // Kernel with an invalid signature.
+    KernelSignatures,
+    // Output or range proof MMR doesn't match the tip.
+    OutputRoot,
+    // Unspent outputs don't match the unpruned leaves of the output MMR.
+    UtxoSet,
+    // Unspent outputs don't sum to the minted supply plus kernel excesses
+    // and the total offset.
+    Supply
+}
+
+// What applying a block changed besides the UTXO set, to rewind it.
+#[derive(Debug, Clone, PartialEq)]
+struct BlockUndo {
+    // Spent outputs with their MMR positions.
+    spent: Vec<(Commitment, u64)>,
+    created: Vec<Commitment>,
+    kernels: usize,
+    // NRD kernel heights dropped from the index as too old.
+    nrd_pruned: Vec<(Commitment, u64)>
+}
+
+// Headers, unspent outputs and MMRs of the chain with the most work.
+// Blocks of other branches are kept to reorganize onto them once they
+// have more work.
+#[derive(Debug, Clone)]
+pub struct ChainState {
+    params: ChainParams,
+    headers: Vec<BlockHeader>,
+    utxos: UtxoSet,
+    // Outputs and their range proofs are appended in lockstep, so they
+    // share positions.
+    output_pmmr: Pmmr,
+    rangeproof_pmmr: Pmmr,
+    kernel_pmmr: Pmmr,
+    // MMR position of every unspent output.
+    positions: HashMap<Commitment, u64>,
+    // Every kernel since genesis.
+    kernels: Vec<TxKernel>,
+    // Heights of the NRD kernels of the last `nrd_max_relative_height`
+    // blocks, by excess.
+    nrd_index: HashMap<Commitment, Vec<u64>>,
+    // Undo data of every block of the chain.
+    undo: Vec<BlockUndo>,
+    // Every known block, by hash.
+    blocks: HashMap<Hash, Block>
+}
+
+impl ChainState {
+    // Start a chain from a genesis block minting its coinbase with
+    // `coinbase_blinding`.
+    pub fn new(
+        secp: &Secp256k1,
+        params: ChainParams,
+        coinbase_blinding: &SecretKey,
+        timestamp: u64
+    ) -> Result<ChainState> {
+        let mut chain = ChainState {
+            params,
+            headers: vec![],
+            utxos: UtxoSet::new(),
+            output_pmmr: Pmmr::new(),
+            rangeproof_pmmr: Pmmr::new(),
+            kernel_pmmr: Pmmr::new(),
+            positions: HashMap::new(),
+            kernels: vec![],
+            nrd_index: HashMap::new(),
+            undo: vec![],
+            blocks: HashMap::new()
+        };
+        let mut genesis = Block::genesis(secp, &chain.params, coinbase_blinding, timestamp)?;
+        chain.set_roots(&mut genesis);
+        genesis.validate(secp, &chain.params, None)?;
+        chain.apply_block(&genesis)?;
+        chain.blocks.insert(genesis.header.hash()?, genesis);
+        Ok(chain)
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    pub fn tip(&self) -> &BlockHeader {
+        &self.headers[self.headers.len() - 1]
+    }
+
+    pub fn header(&self, height: u64) -> Option<&BlockHeader> {
+        self.headers.get(height as usize)
+    }
+
+    pub fn utxos(&self) -> &UtxoSet {
+        &self.utxos
+    }
+
+    // Block on top of the tip, see `Block::new`.
+    pub fn build_block(
+        &mut self,
+        secp: &Secp256k1,
+        txs: Vec<Transaction>,
+        coinbase_blinding: &SecretKey,
+        timestamp: u64
+    ) -> Result<Block> {
+        let tip = self.tip().clone();
+        let mut block = Block::new(secp, &self.params, &tip, txs, coinbase_blinding, timestamp)?;
+        self.set_roots(&mut block);
+        Ok(block)
+    }
+
+    // Fill in the header roots the chain would have with the block applied.
+    fn set_roots(&mut self, block: &mut Block) {
+        let (output_size, kernel_size) = (self.output_pmmr.size(), self.kernel_pmmr.size());
+        self.append_body(&block.body);
+        let header = &mut block.header;
+        header.output_root = self.output_pmmr.root();
+        header.rangeproof_root = self.rangeproof_pmmr.root();
+        header.kernel_root = self.kernel_pmmr.root();
+        header.output_mmr_size = self.output_pmmr.size();
+        header.kernel_mmr_size = self.kernel_pmmr.size();
+        self.rewind_mmrs(output_size, kernel_size);
+    }
+
+    // Validate a block and add it to the chain. Blocks not on top of the tip
+    // go to a side branch, which becomes the chain if it has more work.
+    pub fn process_block(&mut self, secp: &Secp256k1, block: &Block) -> Result<()> {
+        let hash = block.header.hash()?;
+        if self.blocks.contains_key(&hash) {
+            return Err(Error::DuplicateBlock);
+        }
+        let prev = match self.blocks.get(&block.header.previous) {
+            Some(prev) => prev.header.clone(),
+            None => return Err(Error::OrphanBlock)
+        };
+        block.validate(secp, &self.params, Some(&prev))?;
+
+        if block.header.previous == self.tip().hash()? {
+            self.apply_block(block)?;
+        } else if block.header.total_difficulty > self.tip().total_difficulty {
+            self.reorg(block)?;
+        }
+        self.blocks.insert(hash, block.clone());
+        Ok(())
+    }
+
+    fn is_on_chain(&self, hash: &Hash) -> Result<bool> {
+        let header = match self.blocks.get(hash) {
+            Some(block) => &block.header,
+            None => return Ok(false)
+        };
+        match self.header(header.height) {
+            Some(h) => Ok(h.hash()? == *hash),
+            None => Ok(false)
+        }
+    }
+
+    // Switch to the branch ending with `block`. If a block of the branch
+    // doesn't apply, the chain is restored and the block forgotten along
+    // with every block built on it.
+    fn reorg(&mut self, block: &Block) -> Result<()> {
+        let mut branch = vec![block.clone()];
+        let mut previous = block.header.previous;
+        while !self.is_on_chain(&previous)? {
+            let block = self.blocks.get(&previous).ok_or(Error::OrphanBlock)?.clone();
+            previous = block.header.previous;
+            branch.push(block);
+        }
+        branch.reverse();
+
+        let fork_height = branch[0].header.height - 1;
+        let mut abandoned = vec![];
+        for header in &self.headers[fork_height as usize + 1..] {
+            abandoned.push(self.blocks.get(&header.hash()?).ok_or(Error::OrphanBlock)?.clone());
+        }
+
+        self.rewind(fork_height)?;
+        for block in &branch {
+            if let Err(e) = self.apply_block(block) {
+                self.rewind(fork_height)?;
+                for block in &abandoned {
+                    self.apply_block(block)?;
+                }
+                self.forget(block.header.hash()?);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // Drop a known block and its descendants.
+    fn forget(&mut self, hash: Hash) {
+        let mut forgotten = vec![hash];
+        while let Some(hash) = forgotten.pop() {
+            self.blocks.remove(&hash);
+            let children = self.blocks.iter().filter(|(_, b)| b.header.previous == hash);
+            forgotten.extend(children.map(|(child, _)| *child));
+        }
+    }
+
+    // Undo blocks down to `height`.
+    fn rewind(&mut self, height: u64) -> Result<()> {
+        while self.tip().height > height {
+            let undo = self.undo.pop().ok_or(Error::NothingToRollback)?;
+            self.headers.pop();
+            self.utxos.rollback()?;
+            for commit in &undo.created {
+                self.positions.remove(commit);
+            }
+            for (commit, pos) in undo.spent {
+                self.output_pmmr.unprune(pos);
+                self.rangeproof_pmmr.unprune(pos);
+                self.positions.insert(commit, pos);
+            }
+            let removed = self.kernels.split_off(self.kernels.len() - undo.kernels);
+            for kernel in removed.iter().filter(|k| k.is_nrd()) {
+                if let Some(heights) = self.nrd_index.get_mut(&kernel.excess) {
+                    heights.pop();
+                    if heights.is_empty() {
+                        self.nrd_index.remove(&kernel.excess);
+                    }
+                }
+            }
+            for (excess, height) in undo.nrd_pruned.into_iter().rev() {
+                self.nrd_index.entry(excess).or_default().insert(0, height);
+            }
+            let tip = self.tip();
+            let (output_size, kernel_size) = (tip.output_mmr_size, tip.kernel_mmr_size);
+            self.rewind_mmrs(output_size, kernel_size);
+        }
+        Ok(())
+    }
+
+    // NRD kernels included at `height` need a relative height within the
+    // limit, and no NRD kernel with the same excess in the same block or
+    // fewer than that many blocks before.
+    pub fn check_nrd(&self, kernels: &[TxKernel], height: u64) -> Result<()> {
+        let mut excesses = HashSet::new();
+        for kernel in kernels.iter().filter(|k| k.is_nrd()) {
+            if kernel.lock_height == 0 || kernel.lock_height > self.params.nrd_max_relative_height {
+                return Err(Error::InvalidRelativeHeight);
+            }
+            if !excesses.insert(kernel.excess) {
+                return Err(Error::RecentDuplicateKernel);
+            }
+            let last = self.nrd_index.get(&kernel.excess).and_then(|heights| heights.last());
+            if let Some(&last) = last {
+                if height.saturating_sub(last) < kernel.lock_height {
+                    return Err(Error::RecentDuplicateKernel);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_block(&mut self, block: &Block) -> Result<()> {
+        self.utxos.validate_block(&self.params, block)?;
+        self.check_nrd(&block.body.kernels, block.header.height)?;
+
+        let (output_size, kernel_size) = (self.output_pmmr.size(), self.kernel_pmmr.size());
+        let positions = self.append_body(&block.body);
+        let header = &block.header;
+        if header.output_root != self.output_pmmr.root()
+            || header.rangeproof_root != self.rangeproof_pmmr.root()
+            || header.kernel_root != self.kernel_pmmr.root()
+            || header.output_mmr_size != self.output_pmmr.size()
+            || header.kernel_mmr_size != self.kernel_pmmr.size()
+        {
+            self.rewind_mmrs(output_size, kernel_size);
+            return Err(Error::InvalidRoot);
+        }
+
+        self.utxos.apply_block(&self.params, block)?;
+        let mut spent = vec![];
+        for input in &block.body.inputs {
+            if let Some(pos) = self.positions.remove(input) {
+                self.output_pmmr.prune(pos);
+                self.rangeproof_pmmr.prune(pos);
+                spent.push((*input, pos));
+            }
+        }
+        let created = positions.iter().map(|(commit, _)| *commit).collect();
+        self.positions.extend(positions);
+        for kernel in block.body.kernels.iter().filter(|k| k.is_nrd()) {
+            self.nrd_index.entry(kernel.excess).or_default().push(block.header.height);
+        }
+        let nrd_pruned = self.prune_nrd_index(block.header.height);
+        self.kernels.extend(block.body.kernels.iter().cloned());
+        self.headers.push(block.header.clone());
+        let kernels = block.body.kernels.len();
+        self.undo.push(BlockUndo { spent, created, kernels, nrd_pruned });
+        Ok(())
+    }
+
+    // Drop NRD heights too old to conflict with any later kernel, returning
+    // them oldest first.
+    fn prune_nrd_index(&mut self, height: u64) -> Vec<(Commitment, u64)> {
+        let max = self.params.nrd_max_relative_height;
+        let mut pruned = vec![];
+        self.nrd_index.retain(|excess, heights| {
+            let old = heights.iter().take_while(|&&h| height - h >= max).count();
+            pruned.extend(heights.drain(..old).map(|h| (*excess, h)));
+            !heights.is_empty()
+        });
+        pruned
+    }
+
+    // Append outputs and kernels to the MMRs, returning output positions.
+    fn append_body(&mut self, body: &BlockBody) -> Vec<(Commitment, u64)> {
+        let mut positions = vec![];
+        for output in &body.outputs {
+            let pos = self.output_pmmr.append(&output_leaf(output));
+            self.rangeproof_pmmr.append(&rangeproof_leaf(output));
+            positions.push((output.commit, pos));
+        }
+        for kernel in &body.kernels {
+            self.kernel_pmmr.append(&kernel_leaf(kernel));
+        }
+        positions
+    }
+
+    fn rewind_mmrs(&mut self, output_size: u64, kernel_size: u64) {
+        self.output_pmmr.rewind(output_size);
+        self.rangeproof_pmmr.rewind(output_size);
+        self.kernel_pmmr.rewind(kernel_size);
+    }
+
+    // Audit the whole chain state against the tip. Together with range
+    // proofs checked when blocks were applied, it proves that no coins were
+    // created besides the reward:
+    // sum(utxos) - supply * H == sum(kernel excesses) + total_offset * G
+    pub fn validate_full(&self, secp: &Secp256k1) -> Result<()> {
+        let tip = self.tip();
+        let fail = |component| Err(Error::InvalidChainState(component));
+
+        let mut kernel_pmmr = Pmmr::new();
+        for kernel in &self.kernels {
+            kernel_pmmr.append(&kernel_leaf(kernel));
+        }
+        if kernel_pmmr != self.kernel_pmmr
+            || kernel_pmmr.root() != tip.kernel_root
+            || kernel_pmmr.size() != tip.kernel_mmr_size
+        {
+            return fail(ChainComponent::KernelRoot);
+        }
+        if self.kernels.iter().any(|k| k.verify(secp).is_err()) {
+            return fail(ChainComponent::KernelSignatures);
+        }
+
+        if self.output_pmmr.root() != tip.output_root
+            || self.rangeproof_pmmr.root() != tip.rangeproof_root
+            || self.output_pmmr.size() != tip.output_mmr_size
+            || self.rangeproof_pmmr.size() != tip.output_mmr_size
+        {
+            return fail(ChainComponent::OutputRoot);
+        }
+        // The unspent outputs are exactly the unpruned leaves of the MMR.
+        let unpruned = self.utxos.iter().all(|(commit, info)| {
+            self.positions.get(commit).is_some_and(|&pos| {
+                !self.output_pmmr.is_pruned(pos)
+                    && self.output_pmmr.has_leaf(pos, &utxo_leaf(info.features, commit))
+            })
+        });
+        if !unpruned
+            || self.positions.len() != self.utxos.len()
+            || self.output_pmmr.unpruned_leaves() != self.utxos.len() as u64
+        {
+            return fail(ChainComponent::UtxoSet);
+        }
+
+        let positive: Vec<Commitment> = self.utxos.iter().map(|(commit, _)| *commit).collect();
+        let mut negative: Vec<Commitment> = self.kernels.iter().map(|k| k.excess).collect();
+        negative.push(secp.commit_value(self.params.supply(tip.height))?);
+        if tip.total_kernel_offset != ZERO_KEY {
+            negative.push(secp.commit(0, tip.total_kernel_offset)?);
+        }
+        if !secp.verify_commit_sum(positive, negative) {
+            return fail(ChainComponent::Supply);
+        }
+        Ok(())
+    }
+
+    // Proof that an unspent output is in the output MMR as of the tip.
+    pub fn output_proof(&self, commit: &Commitment) -> Option<MerkleProof> {
+        self.positions.get(commit).and_then(|&pos| self.output_pmmr.proof(pos))
+    }
+}
+
+#[test]
+fn test_chain() {
+    use crate::block::transfer;
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+    let params = ChainParams { coinbase_maturity: 1, ..ChainParams::default() };
+
+    let genesis_blinding = rand_blinding(&secp);
+    let mut chain = ChainState::new(&secp, params.clone(), &genesis_blinding, 0).unwrap();
+    assert_eq!(chain.tip().height, 0);
+    assert_eq!(chain.tip().output_mmr_size, 1);
+    assert_eq!(chain.tip().kernel_mmr_size, 1);
+    assert_eq!(chain.utxos().len(), 1);
+
+    let tx = transfer(&secp, params.reward(0), &genesis_blinding, 1_000, 2);
+    let block = chain.build_block(&secp, vec![tx.clone()], &rand_blinding(&secp), 1).unwrap();
+    assert_eq!(chain.tip().height, 0);
+    assert_eq!(chain.process_block(&secp, &block), Ok(()));
+    assert_eq!(chain.tip(), &block.header);
+    assert_eq!(chain.header(0).unwrap().height, 0);
+    assert_eq!(chain.tip().output_mmr_size, 7);
+    assert_eq!(chain.tip().kernel_mmr_size, 4);
+
+    // Unspent outputs are proven against the header, spent ones aren't.
+    for output in &block.body.outputs {
+        let proof = chain.output_proof(&output.commit).unwrap();
+        assert!(verify_output_proof(chain.tip(), output, &proof));
+        assert!(!verify_output_proof(chain.header(0).unwrap(), output, &proof));
+    }
+    let genesis_commit = tx.inputs[0];
+    assert_eq!(chain.output_proof(&genesis_commit), None);
+
+    assert_eq!(chain.process_block(&secp, &block), Err(Error::DuplicateBlock));
+}
+
+#[test]
+fn test_chain_rejects_roots() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let mut chain = ChainState::new(&secp, ChainParams::default(), &rand_blinding(&secp), 0)
+        .unwrap();
+    let block = chain.build_block(&secp, vec![], &rand_blinding(&secp), 1).unwrap();
+
+    let mut other = block.clone();
+    other.header.kernel_root = [1; 32];
+    assert_eq!(chain.process_block(&secp, &other), Err(Error::InvalidRoot));
+    let mut other = block.clone();
+    other.header.output_mmr_size += 1;
+    assert_eq!(chain.process_block(&secp, &other), Err(Error::InvalidRoot));
+
+    // Nothing was applied by the rejected blocks.
+    assert_eq!(chain.tip().height, 0);
+    assert_eq!(chain.utxos().len(), 1);
+    assert_eq!(chain.process_block(&secp, &block), Ok(()));
+    assert_eq!(chain.utxos().len(), 2);
+}
+
+#[test]
+fn test_validate_full() {
+    use crate::block::transfer;
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+    let params = ChainParams {
+        initial_reward: 1_000_000,
+        halving_interval: 2,
+        coinbase_maturity: 0,
+        ..ChainParams::default()
+    };
+
+    let genesis_blinding = rand_blinding(&secp);
+    let mut chain = ChainState::new(&secp, params.clone(), &genesis_blinding, 0).unwrap();
+    assert_eq!(chain.validate_full(&secp), Ok(()));
+
+    // A few blocks across a halving, with fees.
+    let tx = transfer(&secp, params.reward(0), &genesis_blinding, 1_000, 7);
+    let block = chain.build_block(&secp, vec![tx], &rand_blinding(&secp), 1).unwrap();
+    chain.process_block(&secp, &block).unwrap();
+    for timestamp in 2..5 {
+        let block = chain.build_block(&secp, vec![], &rand_blinding(&secp), timestamp).unwrap();
+        chain.process_block(&secp, &block).unwrap();
+    }
+    assert_eq!(chain.tip().height, 4);
+    assert_eq!(chain.validate_full(&secp), Ok(()));
+
+    let check = |chain: &ChainState| chain.validate_full(&secp).unwrap_err();
+
+    let mut other = chain.clone();
+    other.kernels.pop();
+    assert_eq!(check(&other), Error::InvalidChainState(ChainComponent::KernelRoot));
+
+    let mut other = chain.clone();
+    other.kernels[0].excess_sig.partials_sum = rand_blinding(&secp);
+    other.kernel_pmmr = Pmmr::new();
+    for kernel in &other.kernels {
+        other.kernel_pmmr.append(&kernel_leaf(kernel));
+    }
+    let last = other.headers.len() - 1;
+    other.headers[last].kernel_root = other.kernel_pmmr.root();
+    assert_eq!(check(&other), Error::InvalidChainState(ChainComponent::KernelSignatures));
+
+    let mut other = chain.clone();
+    other.headers[last].output_root = [0; 32];
+    assert_eq!(check(&other), Error::InvalidChainState(ChainComponent::OutputRoot));
+
+    let mut other = chain.clone();
+    let pos = *other.positions.values().next().unwrap();
+    other.output_pmmr.prune(pos);
+    assert_eq!(check(&other), Error::InvalidChainState(ChainComponent::UtxoSet));
+
+    // The spent genesis coinbase left unpruned.
+    let mut other = chain.clone();
+    other.output_pmmr.unprune(0);
+    assert_eq!(check(&other), Error::InvalidChainState(ChainComponent::UtxoSet));
+
+    // Outputs pointing at each other's leaves.
+    let mut other = chain.clone();
+    let commits: Vec<Commitment> = other.positions.keys().take(2).cloned().collect();
+    let (a, b) = (other.positions[&commits[0]], other.positions[&commits[1]]);
+    other.positions.insert(commits[0], b);
+    other.positions.insert(commits[1], a);
+    assert_eq!(check(&other), Error::InvalidChainState(ChainComponent::UtxoSet));
+
+    // Inflation hidden in the offset or the reward.
+    let mut other = chain.clone();
+    other.headers[last].total_kernel_offset = rand_blinding(&secp);
+    assert_eq!(check(&other), Error::InvalidChainState(ChainComponent::Supply));
+    let mut other = chain.clone();
+    other.params.initial_reward += 1;
+    assert_eq!(check(&other), Error::InvalidChainState(ChainComponent::Supply));
+}
+
+// Everything but the known blocks matches.
+#[cfg(test)]
+fn assert_same_state(a: &ChainState, b: &ChainState) {
+    assert_eq!(a.params, b.params);
+    assert_eq!(a.headers, b.headers);
+    assert_eq!(a.utxos, b.utxos);
+    assert_eq!(a.output_pmmr, b.output_pmmr);
+    assert_eq!(a.rangeproof_pmmr, b.rangeproof_pmmr);
+    assert_eq!(a.kernel_pmmr, b.kernel_pmmr);
+    assert_eq!(a.positions, b.positions);
+    assert_eq!(a.kernels, b.kernels);
+    assert_eq!(a.nrd_index, b.nrd_index);
+    assert_eq!(a.undo, b.undo);
+}
+
+#[test]
+fn test_reorg() {
+    use crate::block::transfer;
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+    let params = ChainParams { coinbase_maturity: 0, ..ChainParams::default() };
+
+    let genesis_blinding = rand_blinding(&secp);
+    let mut chain = ChainState::new(&secp, params.clone(), &genesis_blinding, 0).unwrap();
+    let block = chain.build_block(&secp, vec![], &rand_blinding(&secp), 1).unwrap();
+    chain.process_block(&secp, &block).unwrap();
+    let fork = chain.clone();
+
+    // Both branches spend the genesis coinbase, differently.
+    let mut a = chain.clone();
+    let tx = transfer(&secp, params.reward(0), &genesis_blinding, 1_000, 2);
+    let a2 = a.build_block(&secp, vec![tx], &rand_blinding(&secp), 2).unwrap();
+    a.process_block(&secp, &a2).unwrap();
+    chain.process_block(&secp, &a2).unwrap();
+
+    let mut b = fork.clone();
+    let tx = transfer(&secp, params.reward(0), &genesis_blinding, 3_000, 5);
+    let b2 = b.build_block(&secp, vec![tx], &rand_blinding(&secp), 2).unwrap();
+    b.process_block(&secp, &b2).unwrap();
+    let b3 = b.build_block(&secp, vec![], &rand_blinding(&secp), 3).unwrap();
+    b.process_block(&secp, &b3).unwrap();
+
+    // Same work: b2 stays on a side branch.
+    assert_eq!(chain.process_block(&secp, &b2), Ok(()));
+    assert_same_state(&chain, &a);
+
+    // More work: switch to b.
+    assert_eq!(chain.process_block(&secp, &b3), Ok(()));
+    assert_same_state(&chain, &b);
+    assert_eq!(chain.validate_full(&secp), Ok(()));
+
+    // Rewinding to the fork point restores it exactly.
+    let mut rewound = chain.clone();
+    rewound.rewind(1).unwrap();
+    assert_same_state(&rewound, &fork);
+
+    // A longer a switches back to it.
+    let a3 = a.build_block(&secp, vec![], &rand_blinding(&secp), 3).unwrap();
+    a.process_block(&secp, &a3).unwrap();
+    assert_eq!(chain.process_block(&secp, &a3), Ok(()));
+    assert_same_state(&chain, &b);
+    let a4 = a.build_block(&secp, vec![], &rand_blinding(&secp), 4).unwrap();
+    a.process_block(&secp, &a4).unwrap();
+    assert_eq!(chain.process_block(&secp, &a4), Ok(()));
+    assert_same_state(&chain, &a);
+    assert_eq!(chain.tip().total_difficulty, 5);
+    assert_eq!(chain.validate_full(&secp), Ok(()));
+}
+
+#[test]
+fn test_reorg_rejects() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let mut chain = ChainState::new(&secp, ChainParams::default(), &rand_blinding(&secp), 0)
+        .unwrap();
+    let fork = chain.clone();
+    let block = chain.build_block(&secp, vec![], &rand_blinding(&secp), 1).unwrap();
+    chain.process_block(&secp, &block).unwrap();
+    let before = chain.clone();
+
+    // Unknown parent.
+    let mut other = fork.clone();
+    let b1 = other.build_block(&secp, vec![], &rand_blinding(&secp), 1).unwrap();
+    other.process_block(&secp, &b1).unwrap();
+    let b2 = other.build_block(&secp, vec![], &rand_blinding(&secp), 2).unwrap();
+    assert_eq!(chain.process_block(&secp, &b2), Err(Error::OrphanBlock));
+
+    // Heavier branch with bad roots: the chain stays as it was.
+    assert_eq!(chain.process_block(&secp, &b1), Ok(()));
+    let mut b2 = b2;
+    b2.header.kernel_root = [1; 32];
+    assert_eq!(chain.process_block(&secp, &b2), Err(Error::InvalidRoot));
+    assert_same_state(&chain, &before);
+    assert_eq!(chain.process_block(&secp, &b2), Err(Error::InvalidRoot));
+}
+
+#[test]
+fn test_reorg_forgets_branch() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+    let params = ChainParams::default();
+
+    let mut chain = ChainState::new(&secp, params.clone(), &rand_blinding(&secp), 0).unwrap();
+    let mut other = chain.clone();
+    for timestamp in 1..5 {
+        let block = chain.build_block(&secp, vec![], &rand_blinding(&secp), timestamp).unwrap();
+        chain.process_block(&secp, &block).unwrap();
+    }
+    let before = chain.clone();
+
+    // Side branch whose second block has bad roots, stored until it gets
+    // heavier than the chain.
+    let b1 = other.build_block(&secp, vec![], &rand_blinding(&secp), 1).unwrap();
+    other.process_block(&secp, &b1).unwrap();
+    let mut b2 = other.build_block(&secp, vec![], &rand_blinding(&secp), 2).unwrap();
+    b2.header.kernel_root = [1; 32];
+    let mut branch = vec![b1, b2];
+    for timestamp in 3..6 {
+        let prev = &branch[branch.len() - 1].header;
+        let block = Block::new(&secp, &params, prev, vec![], &rand_blinding(&secp), timestamp)
+            .unwrap();
+        branch.push(block);
+    }
+    for block in &branch[..4] {
+        assert_eq!(chain.process_block(&secp, block), Ok(()));
+    }
+    assert_same_state(&chain, &before);
+
+    // The reorg fails at b2, which is forgotten with b3 and b4.
+    assert_eq!(chain.process_block(&secp, &branch[4]), Err(Error::InvalidRoot));
+    assert_same_state(&chain, &before);
+    assert_eq!(chain.process_block(&secp, &branch[4]), Err(Error::OrphanBlock));
+    assert_eq!(chain.process_block(&secp, &branch[3]), Err(Error::OrphanBlock));
+    assert_eq!(chain.process_block(&secp, &branch[0]), Err(Error::DuplicateBlock));
+}
+
+// Transaction spending `input` into one output, with an NRD kernel of
+// `excess`: the offset makes up for the difference.
+#[cfg(test)]
+pub(crate) fn nrd_tx(
+    secp: &Secp256k1,
+    input: (u64, SecretKey),
+    fee: u64,
+    relative_height: u64,
+    excess: &SecretKey
+) -> Transaction {
+    use crate::kernel::KernelFeatures;
+
+    let blinding = rand_blinding(secp);
+    let offset = secp.blind_sum(vec![blinding], vec![input.1, *excess]).unwrap();
+    let features = KernelFeatures::NoRecentDuplicate;
+    Transaction {
+        inputs: vec![crate::commit(secp, input.0, &input.1).unwrap()],
+        outputs: vec![Output::new(secp, input.0 - fee, &blinding).unwrap()],
+        kernels: vec![TxKernel::new(secp, features, fee, relative_height, excess).unwrap()],
+        kernel_offset: offset
+    }
+}
+
+#[test]
+fn test_nrd_kernels() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+    let params = ChainParams {
+        coinbase_maturity: 0,
+        nrd_max_relative_height: 5,
+        ..ChainParams::default()
+    };
+    let reward = params.reward(0);
+
+    // Coinbase outputs to spend at heights 0 to 3.
+    let blindings: Vec<SecretKey> = (0..4).map(|_| rand_blinding(&secp)).collect();
+    let mut chain = ChainState::new(&secp, params, &blindings[0], 0).unwrap();
+    for (i, blinding) in blindings.iter().enumerate().skip(1) {
+        let block = chain.build_block(&secp, vec![], blinding, i as u64).unwrap();
+        chain.process_block(&secp, &block).unwrap();
+    }
+
+    // Transactions sharing the same kernel excess, as in a payment channel.
+    let excess = rand_blinding(&secp);
+    let tx1 = nrd_tx(&secp, (reward, blindings[0]), 2, 2, &excess);
+    let tx2 = nrd_tx(&secp, (reward, blindings[1]), 2, 2, &excess);
+    let tx3 = nrd_tx(&secp, (reward, blindings[2]), 2, 2, &excess);
+    assert_eq!(tx1.validate(&secp), Ok(()));
+    assert_eq!(tx1.kernels[0].excess, tx2.kernels[0].excess);
+
+    // Relative height out of bounds.
+    let tx = nrd_tx(&secp, (reward, blindings[3]), 2, 0, &rand_blinding(&secp));
+    assert_eq!(chain.check_nrd(&tx.kernels, 4), Err(Error::InvalidRelativeHeight));
+    let tx = nrd_tx(&secp, (reward, blindings[3]), 2, 6, &rand_blinding(&secp));
+    assert_eq!(chain.check_nrd(&tx.kernels, 4), Err(Error::InvalidRelativeHeight));
+
+    // Not twice in the same block.
+    let block = chain.build_block(&secp, vec![tx1.clone(), tx2.clone()], &rand_blinding(&secp), 4)
+        .unwrap();
+    assert_eq!(chain.process_block(&secp, &block), Err(Error::RecentDuplicateKernel));
+
+    let block = chain.build_block(&secp, vec![tx1], &rand_blinding(&secp), 4).unwrap();
+    chain.process_block(&secp, &block).unwrap();
+    let before = chain.clone();
+
+    // One block later is too recent, two blocks later is fine.
+    let block = chain.build_block(&secp, vec![tx2.clone()], &rand_blinding(&secp), 5).unwrap();
+    assert_eq!(chain.process_block(&secp, &block), Err(Error::RecentDuplicateKernel));
+    let block = chain.build_block(&secp, vec![], &rand_blinding(&secp), 5).unwrap();
+    chain.process_block(&secp, &block).unwrap();
+    assert_eq!(chain.check_nrd(&tx3.kernels, 6), Ok(()));
+    let block = chain.build_block(&secp, vec![tx2.clone()], &rand_blinding(&secp), 6).unwrap();
+    assert_eq!(chain.process_block(&secp, &block), Ok(()));
+    assert_eq!(chain.check_nrd(&tx3.kernels, 7), Err(Error::RecentDuplicateKernel));
+    assert_eq!(chain.nrd_index[&tx2.kernels[0].excess], vec![4, 6]);
+    assert_eq!(chain.check_nrd(&tx3.kernels, 0), Err(Error::RecentDuplicateKernel));
+
+    // Only the last blocks are indexed.
+    for timestamp in 7..10 {
+        let block = chain.build_block(&secp, vec![], &rand_blinding(&secp), timestamp).unwrap();
+        chain.process_block(&secp, &block).unwrap();
+    }
+    assert_eq!(chain.nrd_index[&tx2.kernels[0].excess], vec![6]);
+    assert_eq!(chain.validate_full(&secp), Ok(()));
+
+    // Rewinding forgets the rewound kernels and restores pruned ones.
+    chain.rewind(4).unwrap();
+    assert_same_state(&chain, &before);
+    assert_eq!(chain.check_nrd(&tx3.kernels, 6), Ok(()));
+}
@@ -0,0 +1,143 @@
+// Coins minted by the first blocks, in the smallest unit.
+pub const INITIAL_REWARD: u64 = 60_000_000_000;
+
+// Blocks between two halvings of the reward.
+pub const HALVING_INTERVAL: u64 = 1_000_000;
+
+// Blocks before a coinbase output can be spent.
+pub const COINBASE_MATURITY: u64 = 1_440;
+
+// Work of every block. Without proof of work, a block can't claim more,
+// so the chain with the most work is the longest one.
+pub const BLOCK_DIFFICULTY: u64 = 1;
+
+// Largest relative height of NRD kernels, a week of one minute blocks.
+pub const NRD_MAX_RELATIVE_HEIGHT: u64 = 10_080;
+
+// Weights of transaction parts, roughly what they cost to verify and store.
+pub const INPUT_WEIGHT: u64 = 1;
+pub const OUTPUT_WEIGHT: u64 = 21;
+pub const KERNEL_WEIGHT: u64 = 3;
+
+// Fee per unit of weight needed to relay a transaction.
+pub const MIN_FEE_RATE: u64 = 1_000;
+
+pub fn weight(inputs: usize, outputs: usize, kernels: usize) -> u64 {
+    inputs as u64 * INPUT_WEIGHT + outputs as u64 * OUTPUT_WEIGHT + kernels as u64 * KERNEL_WEIGHT
+}
+
+// Parameters the chain is run with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainParams {
+    pub initial_reward: u64,
+    // Blocks between two halvings of the reward, 0 to never halve.
+    pub halving_interval: u64,
+    // Blocks before a coinbase output can be spent.
+    pub coinbase_maturity: u64,
+    // Fee per unit of weight needed to relay a transaction.
+    pub min_fee_rate: u64,
+    // Largest relative height of NRD kernels.
+    pub nrd_max_relative_height: u64
+}
+
+impl Default for ChainParams {
+    fn default() -> ChainParams {
+        ChainParams {
+            initial_reward: INITIAL_REWARD,
+            halving_interval: HALVING_INTERVAL,
+            coinbase_maturity: COINBASE_MATURITY,
+            min_fee_rate: MIN_FEE_RATE,
+            nrd_max_relative_height: NRD_MAX_RELATIVE_HEIGHT
+        }
+    }
+}
+
+impl ChainParams {
+    // Coins minted by the block at `height`: the initial reward halved every
+    // `halving_interval` blocks, down to nothing.
+    pub fn reward(&self, height: u64) -> u64 {
+        if self.halving_interval == 0 {
+            return self.initial_reward;
+        }
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            0
+        } else {
+            self.initial_reward >> halvings
+        }
+    }
+
+    // Coins minted by all blocks up to and including `height`.
+    pub fn supply(&self, height: u64) -> u64 {
+        if self.halving_interval == 0 {
+            return self.initial_reward.saturating_mul(height.saturating_add(1));
+        }
+        let mut supply = 0u64;
+        let mut start = 0u64;
+        while start <= height && self.reward(start) != 0 {
+            let end = height.min(start.saturating_add(self.halving_interval - 1));
+            let blocks = (end - start).saturating_add(1);
+            supply = supply.saturating_add(self.reward(start).saturating_mul(blocks));
+            start = match start.checked_add(self.halving_interval) {
+                Some(next) => next,
+                None => break
+            };
+        }
+        supply
+    }
+
+    // Lowest fee relayed for a transaction of `weight`.
+    pub fn min_fee(&self, weight: u64) -> u64 {
+        weight.saturating_mul(self.min_fee_rate)
+    }
+
+    // First height at which a coinbase output minted at `height` can be spent.
+    pub fn coinbase_spendable_at(&self, height: u64) -> u64 {
+        height.saturating_add(self.coinbase_maturity)
+    }
+}
+
+#[test]
+fn test_reward() {
+    let params = ChainParams {
+        initial_reward: 100,
+        halving_interval: 10,
+        ..ChainParams::default()
+    };
+    assert_eq!(params.reward(0), 100);
+    assert_eq!(params.reward(9), 100);
+    assert_eq!(params.reward(10), 50);
+    assert_eq!(params.reward(25), 25);
+    assert_eq!(params.reward(70), 0);
+    assert_eq!(params.reward(u64::MAX), 0);
+
+    assert_eq!(params.supply(0), 100);
+    assert_eq!(params.supply(9), 1_000);
+    assert_eq!(params.supply(12), 1_150);
+    assert_eq!(params.supply(1_000), (0..=1_000).map(|h| params.reward(h)).sum::<u64>());
+
+    let params = ChainParams { halving_interval: 0, ..params };
+    assert_eq!(params.reward(u64::MAX), 100);
+    assert_eq!(params.supply(9), 1_000);
+
+    assert_eq!(ChainParams::default().reward(0), INITIAL_REWARD);
+
+    // Large heights and intervals saturate instead of overflowing.
+    let params = ChainParams::default();
+    assert_eq!(params.supply(u64::MAX), params.supply(64 * HALVING_INTERVAL));
+    let params = ChainParams { halving_interval: u64::MAX, ..ChainParams::default() };
+    assert_eq!(params.supply(u64::MAX), u64::MAX);
+    let params = ChainParams { halving_interval: 0, ..ChainParams::default() };
+    assert_eq!(params.supply(u64::MAX), u64::MAX);
+}
+
+#[test]
+fn test_fee() {
+    assert_eq!(weight(0, 0, 0), 0);
+    assert_eq!(weight(1, 2, 1), 46);
+    assert_eq!(weight(2, 1, 1), 26);
+
+    let params = ChainParams { min_fee_rate: 10, ..ChainParams::default() };
+    assert_eq!(params.min_fee(weight(1, 2, 1)), 460);
+    assert_eq!(params.min_fee(u64::MAX), u64::MAX);
+}
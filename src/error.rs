@@ -0,0 +1,115 @@
+use std::fmt;
+
+use crate::chain::ChainComponent;
+use crate::ser;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    // Error from the underlying secp256k1 library.
+    Secp(secp256k1::Error),
+    // Malformed binary data.
+    Ser(ser::Error),
+    // Amounts of a transfer don't add up, e.g. the input doesn't cover
+    // the amount and fee.
+    AmountMismatch,
+    // Counterparty's partial signature doesn't verify.
+    BadPartialSignature,
+    // Participant's contribution isn't part of the transaction it's asked
+    // to sign.
+    MissingContribution,
+    // Receiver's output doesn't commit to the amount with its public blinding.
+    OutputMismatch,
+    // Transaction without inputs or outputs.
+    EmptyTransaction,
+    // Inputs and outputs don't sum up to the kernel excess.
+    UnbalancedTransaction,
+    // Kernel fees, or fees and reward, add up to more than a u64.
+    FeeOverflow,
+    // Aggregated signature doesn't verify against the kernel excess.
+    IncorrectSignature,
+    // Block header doesn't follow the previous one.
+    InvalidBlockHeader,
+    // Block was already processed.
+    DuplicateBlock,
+    // Block's parent is unknown.
+    OrphanBlock,
+    // Header MMR roots or sizes don't match the chain.
+    InvalidRoot,
+    // Coinbase doesn't mint exactly the reward plus fees.
+    InvalidCoinbase,
+    // Input doesn't spend an unspent output.
+    MissingInput,
+    // Fee below the minimum relay fee for the transaction's weight.
+    FeeTooLow,
+    // Input already spent by a pool transaction.
+    DoubleSpend,
+    // Output already exists.
+    DuplicateOutput,
+    // No block or transaction left to roll back.
+    NothingToRollback,
+    // Kernel locked until a later height.
+    LockedKernel,
+    // Kernel's lock height doesn't match its features.
+    InvalidLockHeight,
+    // NRD kernel with the same excess as one too close to it.
+    RecentDuplicateKernel,
+    // NRD kernel's relative height is 0 or too large.
+    InvalidRelativeHeight,
+    // Input spends a coinbase output before it matured.
+    ImmatureCoinbase,
+    // Block outputs don't match inputs, kernels and the reward.
+    UnbalancedBlock,
+    // Full validation of the chain state failed at the given component.
+    InvalidChainState(ChainComponent),
+    // Range proof doesn't prove the output value is in [0, 2^64).
+    InvalidRangeProof
+}
+
+impl From<secp256k1::Error> for Error {
+    fn from(e: secp256k1::Error) -> Error {
+        Error::Secp(e)
+    }
+}
+
+impl From<ser::Error> for Error {
+    fn from(e: ser::Error) -> Error {
+        Error::Ser(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Secp(e) => write!(f, "secp256k1 error: {}", e),
+            Error::Ser(e) => write!(f, "serialization error: {}", e),
+            Error::AmountMismatch => write!(f, "amounts don't add up"),
+            Error::BadPartialSignature => write!(f, "bad partial signature"),
+            Error::MissingContribution => write!(f, "contribution missing from transaction"),
+            Error::OutputMismatch => write!(f, "output doesn't match amount and blinding"),
+            Error::EmptyTransaction => write!(f, "transaction has no inputs or outputs"),
+            Error::UnbalancedTransaction => write!(f, "transaction doesn't balance"),
+            Error::FeeOverflow => write!(f, "fees overflow"),
+            Error::IncorrectSignature => write!(f, "incorrect kernel signature"),
+            Error::InvalidBlockHeader => write!(f, "invalid block header"),
+            Error::DuplicateBlock => write!(f, "duplicate block"),
+            Error::OrphanBlock => write!(f, "unknown previous block"),
+            Error::InvalidRoot => write!(f, "invalid MMR root"),
+            Error::InvalidCoinbase => write!(f, "invalid coinbase"),
+            Error::MissingInput => write!(f, "input spends a missing or spent output"),
+            Error::FeeTooLow => write!(f, "fee too low"),
+            Error::DoubleSpend => write!(f, "input already spent in the pool"),
+            Error::DuplicateOutput => write!(f, "output already exists"),
+            Error::NothingToRollback => write!(f, "nothing to roll back"),
+            Error::LockedKernel => write!(f, "kernel locked until a later height"),
+            Error::InvalidLockHeight => write!(f, "lock height doesn't match kernel features"),
+            Error::RecentDuplicateKernel => write!(f, "NRD kernel duplicated too recently"),
+            Error::InvalidRelativeHeight => write!(f, "invalid NRD relative height"),
+            Error::ImmatureCoinbase => write!(f, "coinbase output spent before maturity"),
+            Error::UnbalancedBlock => write!(f, "block doesn't balance"),
+            Error::InvalidChainState(c) => write!(f, "invalid chain state: {:?}", c),
+            Error::InvalidRangeProof => write!(f, "invalid range proof")
+        }
+    }
+}
+
+impl std::error::Error for Error {}
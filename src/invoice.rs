@@ -0,0 +1,272 @@
+use secp256k1::{
+    Secp256k1, ContextFlag,
+    key::SecretKey,
+    pedersen::Commitment,
+};
+use rand::thread_rng;
+
+use crate::{commit, rand_blinding, sign_partial, verify_partial, Error, Result};
+use crate::protocol::{
+    Message, Response, Sender, SenderKeys, Receiver, ReceiverKeys, verify_output,
+};
+use crate::transaction::{Output, Transaction};
+use crate::ser::{Writeable, Readable, Writer, Reader};
+
+// Sent by the receiver to request a payment. Same as a `Response` without
+// the partial signature, which can't be made before the payer's nonce and
+// blinding are known.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Invoice {
+    pub amount: u64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::slate::commitment_hex"))]
+    pub nonce: Commitment,
+    #[cfg_attr(feature = "serde", serde(with = "crate::slate::commitment_hex"))]
+    pub blinding: Commitment,
+    pub output: Output
+}
+
+impl Invoice {
+    // Check that the output commits to `amount` with the blinding factor
+    // behind the public blinding, like `Response::verify`.
+    pub fn verify(&self, secp: &Secp256k1) -> Result<()> {
+        if self.amount == 0 {
+            return Err(Error::AmountMismatch);
+        }
+        verify_output(secp, &self.output, self.amount, &self.blinding)
+    }
+}
+
+// Sent back by the payer: the usual sender's message together with the
+// payer's partial signature.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Payment {
+    pub message: Message,
+    #[cfg_attr(feature = "serde", serde(with = "crate::slate::secret_key_hex"))]
+    pub sign: SecretKey
+}
+
+impl Writeable for Invoice {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        writer.write_u64(self.amount);
+        self.nonce.write(writer)?;
+        self.blinding.write(writer)?;
+        self.output.write(writer)
+    }
+}
+
+impl Readable for Invoice {
+    fn read(reader: &mut Reader) -> Result<Invoice> {
+        Ok(Invoice {
+            amount: reader.read_u64()?,
+            nonce: Commitment::read(reader)?,
+            blinding: Commitment::read(reader)?,
+            output: Output::read(reader)?
+        })
+    }
+}
+
+impl Writeable for Payment {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        self.message.write(writer)?;
+        self.sign.write(writer)
+    }
+}
+
+impl Readable for Payment {
+    fn read(reader: &mut Reader) -> Result<Payment> {
+        Ok(Payment {
+            message: Message::read(reader)?,
+            sign: SecretKey::read(reader)?
+        })
+    }
+}
+
+// Receiver's side of an invoice.
+pub struct Payee {
+    pub invoice: Invoice,
+    output_blinding: SecretKey,
+    nonce: SecretKey
+}
+
+impl Payee {
+    // Request `amount` to be paid into a new output.
+    pub fn invoice(secp: &Secp256k1, amount: u64) -> Result<Payee> {
+        Payee::invoice_with_keys(secp, amount, ReceiverKeys::random(secp))
+    }
+
+    pub(crate) fn invoice_with_keys(
+        secp: &Secp256k1,
+        amount: u64,
+        keys: ReceiverKeys
+    ) -> Result<Payee> {
+        if amount == 0 {
+            return Err(Error::AmountMismatch);
+        }
+        let invoice = Invoice {
+            amount,
+            nonce: commit(secp, 0, &keys.nonce)?,
+            blinding: commit(secp, 0, &keys.output_blinding)?,
+            output: Output::new(secp, amount, &keys.output_blinding)?
+        };
+        Ok(Payee { invoice, output_blinding: keys.output_blinding, nonce: keys.nonce })
+    }
+
+    pub fn output_blinding(&self) -> &SecretKey {
+        &self.output_blinding
+    }
+
+    // Check the payer's message and partial signature, add ours and build
+    // the final transaction. Consumes the payee, as signing another payment
+    // with the same nonce would leak the output blinding.
+    pub fn finalize(self, secp: &Secp256k1, payment: &Payment) -> Result<Transaction> {
+        let msg = &payment.message;
+        if msg.amount != self.invoice.amount {
+            return Err(Error::AmountMismatch);
+        }
+        msg.verify(secp)?;
+
+        let e = msg.challenge(secp, &self.invoice.nonce, &self.invoice.blinding)?;
+        verify_partial(secp, &payment.sign, &msg.nonce, &msg.sum_of_bliding_factors, &e)?;
+
+        let response = Response {
+            sign: sign_partial(secp, &self.output_blinding, &self.nonce, &e)?,
+            nonce: self.invoice.nonce,
+            blinding: self.invoice.blinding,
+            output: self.invoice.output.clone()
+        };
+        msg.finalize(secp, &response, &payment.sign)
+    }
+}
+
+// Payer's side of an invoice.
+pub struct Payer {
+    pub payment: Payment,
+    sender: Sender
+}
+
+impl Payer {
+    // Check the invoice, spend the input worth `input_value` to pay it
+    // with `fee` and sign.
+    pub fn pay(
+        secp: &Secp256k1,
+        invoice: &Invoice,
+        input_value: u64,
+        input_blinding: &SecretKey,
+        fee: u64
+    ) -> Result<Payer> {
+        let keys = SenderKeys::random(secp);
+        Payer::pay_with_keys(secp, invoice, input_value, input_blinding, fee, keys)
+    }
+
+    pub(crate) fn pay_with_keys(
+        secp: &Secp256k1,
+        invoice: &Invoice,
+        input_value: u64,
+        input_blinding: &SecretKey,
+        fee: u64,
+        keys: SenderKeys
+    ) -> Result<Payer> {
+        invoice.verify(secp)?;
+        let sender = Sender::initiate_with_keys(
+            secp,
+            input_value,
+            input_blinding,
+            invoice.amount,
+            fee,
+            keys
+        )?;
+        let sign = sender.sign(secp, &invoice.nonce, &invoice.blinding)?;
+        let payment = Payment { message: sender.message.clone(), sign };
+        Ok(Payer { payment, sender })
+    }
+
+    pub fn change_blinding(&self) -> &SecretKey {
+        self.sender.change_blinding()
+    }
+}
+
+#[test]
+fn test_invoice() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    // Bob asks for 25.
+    let bob = Payee::invoice(&secp, 25).unwrap();
+
+    // Alice pays it out of 40 with a fee of 2.
+    let ali_input_blinding = rand_blinding(&secp);
+    let ali = Payer::pay(&secp, &bob.invoice, 40, &ali_input_blinding, 2).unwrap();
+    assert_eq!(
+        ali.payment.message.change_output.commit,
+        commit(&secp, 13, ali.change_blinding()).unwrap()
+    );
+
+    // Bob finalizes.
+    let bob_output_blinding = *bob.output_blinding();
+    let tx = bob.finalize(&secp, &ali.payment).unwrap();
+    assert_eq!(tx.validate(&secp), Ok(()));
+    assert_eq!(tx.outputs[1].commit, commit(&secp, 25, &bob_output_blinding).unwrap());
+}
+
+#[test]
+fn test_invoice_same_as_transfer() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let input_blinding = rand_blinding(&secp);
+    let sender_keys = SenderKeys::random(&secp);
+    let receiver_keys = ReceiverKeys::random(&secp);
+
+    // Sender initiates.
+    let ali = Sender::initiate_with_keys(
+        &secp, 40, &input_blinding, 25, 2, sender_keys.clone()
+    ).unwrap();
+    let bob = Receiver::respond_with_keys(&secp, &ali.message, receiver_keys.clone()).unwrap();
+    let transfer = ali.finalize(&secp, &bob.response).unwrap();
+
+    // Receiver initiates.
+    let bob = Payee::invoice_with_keys(&secp, 25, receiver_keys).unwrap();
+    let ali = Payer::pay_with_keys(
+        &secp, &bob.invoice, 40, &input_blinding, 2, sender_keys
+    ).unwrap();
+    let invoice = bob.finalize(&secp, &ali.payment).unwrap();
+
+    // Same transaction, up to the randomness of the range proofs.
+    assert_eq!(transfer.validate(&secp), Ok(()));
+    assert_eq!(invoice.validate(&secp), Ok(()));
+    assert_eq!(transfer.inputs, invoice.inputs);
+    let commits = |tx: &Transaction| tx.outputs.iter().map(|o| o.commit).collect::<Vec<_>>();
+    assert_eq!(commits(&transfer), commits(&invoice));
+    assert_eq!(transfer.kernels, invoice.kernels);
+    assert_eq!(transfer.kernel_offset, invoice.kernel_offset);
+}
+
+#[test]
+fn test_invoice_rejects() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let bob = Payee::invoice(&secp, 25).unwrap();
+    let input_blinding = rand_blinding(&secp);
+
+    // Invoice output not matching the amount.
+    let mut invoice = bob.invoice.clone();
+    invoice.amount = 24;
+    assert_eq!(
+        Payer::pay(&secp, &invoice, 40, &input_blinding, 2).err(),
+        Some(Error::OutputMismatch)
+    );
+
+    // Payment of a different amount.
+    let other = Payee::invoice(&secp, 24).unwrap();
+    let ali = Payer::pay(&secp, &other.invoice, 40, &input_blinding, 2).unwrap();
+    assert_eq!(bob.finalize(&secp, &ali.payment).err(), Some(Error::AmountMismatch));
+
+    // Bad payer's signature. Finalizing consumed Bob, so he sends a new invoice.
+    let bob = Payee::invoice(&secp, 25).unwrap();
+    let mut ali = Payer::pay(&secp, &bob.invoice, 40, &input_blinding, 2).unwrap();
+    ali.payment.sign = rand_blinding(&secp);
+    assert_eq!(bob.finalize(&secp, &ali.payment).err(), Some(Error::BadPartialSignature));
+}
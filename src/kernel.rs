@@ -0,0 +1,277 @@
+use secp256k1::{
+    Secp256k1, ContextFlag,
+    key::SecretKey,
+    pedersen::Commitment,
+};
+use sha2::{Sha256, Digest};
+use rand::thread_rng;
+
+use crate::{commit, challenge, rand_blinding, sign_partial, Hash, Error, Result};
+use crate::ser::{self, Writeable, Readable, Writer, Reader};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelFeatures {
+    // Regular transaction kernel.
+    Plain,
+    // Kernel of the coinbase output minted by a block.
+    Coinbase,
+    // Kernel that can't be included in a block below `lock_height`.
+    HeightLocked,
+    // No-Recent-Duplicate kernel, whose `lock_height` is a relative height:
+    // it can't be included within `lock_height` blocks of another NRD kernel
+    // with the same excess.
+    NoRecentDuplicate
+}
+
+impl KernelFeatures {
+    pub fn as_u8(self) -> u8 {
+        match self {
+            KernelFeatures::Plain => 0,
+            KernelFeatures::Coinbase => 1,
+            KernelFeatures::HeightLocked => 2,
+            KernelFeatures::NoRecentDuplicate => 3
+        }
+    }
+
+    // Features of a transaction kernel locked until `lock_height`.
+    pub fn for_lock_height(lock_height: u64) -> KernelFeatures {
+        if lock_height == 0 {
+            KernelFeatures::Plain
+        } else {
+            KernelFeatures::HeightLocked
+        }
+    }
+
+    pub fn from_u8(b: u8) -> Option<KernelFeatures> {
+        match b {
+            0 => Some(KernelFeatures::Plain),
+            1 => Some(KernelFeatures::Coinbase),
+            2 => Some(KernelFeatures::HeightLocked),
+            3 => Some(KernelFeatures::NoRecentDuplicate),
+            _ => None
+        }
+    }
+}
+
+// Message signed by the kernel:
+// features byte, big-endian fee and big-endian lock height.
+pub fn kernel_message(features: KernelFeatures, fee: u64, lock_height: u64) -> Vec<u8> {
+    let mut msg = vec![features.as_u8()];
+    msg.extend_from_slice(&fee.to_be_bytes());
+    msg.extend_from_slice(&lock_height.to_be_bytes());
+    msg
+}
+
+impl Writeable for KernelFeatures {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        writer.write_u8(self.as_u8());
+        Ok(())
+    }
+}
+
+impl Readable for KernelFeatures {
+    fn read(reader: &mut Reader) -> Result<KernelFeatures> {
+        KernelFeatures::from_u8(reader.read_u8()?)
+            .ok_or_else(|| ser::Error::CorruptedData.into())
+    }
+}
+
+// Aggregated Schnorr signature of a kernel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxSignature {
+    pub partials_sum: SecretKey,
+    pub nonces_sum: Commitment
+}
+
+impl TxSignature {
+    // Verify the aggregated signature against the kernel excess:
+    // partials_sum * G == nonces_sum + e * excess
+    pub fn verify(
+        &self,
+        secp: &Secp256k1,
+        excess: &Commitment,
+        msg: &[u8]
+    ) -> Result<()> {
+        let e = challenge(secp, &self.nonces_sum, excess, msg)?;
+        let partials_sum = secp.commit(0, self.partials_sum)?;
+        let left = secp.commit_sum(vec![partials_sum], vec![self.nonces_sum])?
+            .to_pubkey(secp)?;
+        let mut right = excess.to_pubkey(secp)?;
+        right.mul_assign(secp, &e)?;
+        if left == right {
+            Ok(())
+        } else {
+            Err(Error::IncorrectSignature)
+        }
+    }
+}
+
+impl Writeable for TxSignature {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        self.partials_sum.write(writer)?;
+        self.nonces_sum.write(writer)
+    }
+}
+
+impl Readable for TxSignature {
+    fn read(reader: &mut Reader) -> Result<TxSignature> {
+        Ok(TxSignature {
+            partials_sum: SecretKey::read(reader)?,
+            nonces_sum: Commitment::read(reader)?
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxKernel {
+    pub features: KernelFeatures,
+    pub fee: u64,
+    pub lock_height: u64,
+    // Public part of the transaction's blinding factors.
+    pub excess: Commitment,
+    pub excess_sig: TxSignature
+}
+
+impl TxKernel {
+    // Kernel signed by a single party knowing the whole excess.
+    pub fn new(
+        secp: &Secp256k1,
+        features: KernelFeatures,
+        fee: u64,
+        lock_height: u64,
+        excess: &SecretKey
+    ) -> Result<TxKernel> {
+        let nonce = rand_blinding(secp);
+        let nonces_sum = commit(secp, 0, &nonce)?;
+        let excess_commit = commit(secp, 0, excess)?;
+        let msg = kernel_message(features, fee, lock_height);
+        let e = challenge(secp, &nonces_sum, &excess_commit, &msg)?;
+
+        Ok(TxKernel {
+            features,
+            fee,
+            lock_height,
+            excess: excess_commit,
+            excess_sig: TxSignature {
+                partials_sum: sign_partial(secp, excess, &nonce, &e)?,
+                nonces_sum
+            }
+        })
+    }
+
+    // Lowest height of a block the kernel can be included in.
+    pub fn lock_height(&self) -> u64 {
+        match self.features {
+            KernelFeatures::HeightLocked => self.lock_height,
+            _ => 0
+        }
+    }
+
+    pub fn is_nrd(&self) -> bool {
+        self.features == KernelFeatures::NoRecentDuplicate
+    }
+
+    pub fn msg(&self) -> Vec<u8> {
+        kernel_message(self.features, self.fee, self.lock_height)
+    }
+
+    // Only height locked and NRD kernels have a lock height, and height
+    // locked ones always do, so each kernel has a single encoding.
+    pub fn verify(&self, secp: &Secp256k1) -> Result<()> {
+        let consistent = match self.features {
+            KernelFeatures::Plain | KernelFeatures::Coinbase => self.lock_height == 0,
+            KernelFeatures::HeightLocked => self.lock_height != 0,
+            KernelFeatures::NoRecentDuplicate => true
+        };
+        if !consistent {
+            return Err(Error::InvalidLockHeight);
+        }
+        self.excess_sig.verify(secp, &self.excess, &self.msg())
+    }
+
+    pub fn hash(&self) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.input(self.msg());
+        hasher.input(&self.excess.0[..]);
+        hasher.input(&self.excess_sig.nonces_sum.0[..]);
+        hasher.input(&self.excess_sig.partials_sum.0[..]);
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&hasher.result());
+        hash
+    }
+}
+
+impl Writeable for TxKernel {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        self.features.write(writer)?;
+        writer.write_u64(self.fee);
+        writer.write_u64(self.lock_height);
+        self.excess.write(writer)?;
+        self.excess_sig.write(writer)
+    }
+}
+
+impl Readable for TxKernel {
+    fn read(reader: &mut Reader) -> Result<TxKernel> {
+        Ok(TxKernel {
+            features: KernelFeatures::read(reader)?,
+            fee: reader.read_u64()?,
+            lock_height: reader.read_u64()?,
+            excess: Commitment::read(reader)?,
+            excess_sig: TxSignature::read(reader)?
+        })
+    }
+}
+
+#[test]
+fn test_kernel() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let excess = rand_blinding(&secp);
+    let kernel = TxKernel::new(&secp, KernelFeatures::HeightLocked, 3, 100, &excess).unwrap();
+    assert_eq!(kernel.verify(&secp), Ok(()));
+    assert_eq!(kernel.excess, commit(&secp, 0, &excess).unwrap());
+
+    // Features, fee and lock height are all signed.
+    let mut other = kernel.clone();
+    other.features = KernelFeatures::NoRecentDuplicate;
+    assert_eq!(other.verify(&secp), Err(Error::IncorrectSignature));
+    assert_ne!(other.hash(), kernel.hash());
+
+    let mut other = kernel.clone();
+    other.fee = 4;
+    assert_eq!(other.verify(&secp), Err(Error::IncorrectSignature));
+    assert_ne!(other.hash(), kernel.hash());
+
+    let mut other = kernel.clone();
+    other.lock_height = 99;
+    assert_eq!(other.verify(&secp), Err(Error::IncorrectSignature));
+    assert_ne!(other.hash(), kernel.hash());
+
+    // Lock height not matching the features, even if signed.
+    let other = TxKernel::new(&secp, KernelFeatures::Plain, 3, 100, &excess).unwrap();
+    assert_eq!(other.verify(&secp), Err(Error::InvalidLockHeight));
+    let other = TxKernel::new(&secp, KernelFeatures::HeightLocked, 3, 0, &excess).unwrap();
+    assert_eq!(other.verify(&secp), Err(Error::InvalidLockHeight));
+    let other = TxKernel::new(&secp, KernelFeatures::Coinbase, 0, 1, &excess).unwrap();
+    assert_eq!(other.verify(&secp), Err(Error::InvalidLockHeight));
+
+    // Excess signed by someone else.
+    let mut other = kernel.clone();
+    other.excess = commit(&secp, 0, &rand_blinding(&secp)).unwrap();
+    assert_eq!(other.verify(&secp), Err(Error::IncorrectSignature));
+}
+
+#[test]
+fn test_kernel_features() {
+    for features in &[
+        KernelFeatures::Plain,
+        KernelFeatures::Coinbase,
+        KernelFeatures::HeightLocked,
+        KernelFeatures::NoRecentDuplicate
+    ] {
+        assert_eq!(KernelFeatures::from_u8(features.as_u8()), Some(*features));
+    }
+    assert_eq!(KernelFeatures::from_u8(4), None);
+}
@@ -5,211 +5,224 @@
 use secp256k1::{
     Secp256k1, ContextFlag,
     key::{SecretKey, PublicKey, ZERO_KEY, ONE_KEY}, 
-    pedersen::Commitment,
+    pedersen::{Commitment, RangeProof},
 };
 use sha2::{Sha256, Digest};
 use rand::thread_rng;
 
-struct Message {
-    amount: u64,
-    input: Commitment,
-    change_output: Commitment,
-    nonce: Commitment,
-    sum_of_bliding_factors: Commitment
-}
-
-struct Response {
-    sign: SecretKey,
-    nonce: Commitment,
-    blinding: Commitment,
-    output: Commitment
+pub mod block;
+pub mod chain;
+pub mod consensus;
+pub mod error;
+pub mod invoice;
+pub mod kernel;
+pub mod multiparty;
+pub mod pmmr;
+pub mod pool;
+pub mod protocol;
+pub mod ser;
+#[cfg(feature = "serde")]
+pub mod slate;
+pub mod transaction;
+pub mod utxo;
+
+pub use crate::block::{Block, BlockHeader, BlockBody};
+pub use crate::chain::{ChainComponent, ChainState};
+pub use crate::consensus::ChainParams;
+pub use crate::error::Error;
+pub use crate::invoice::{Invoice, Payment, Payee, Payer};
+pub use crate::kernel::{KernelFeatures, TxSignature, TxKernel, kernel_message};
+pub use crate::multiparty::{Contribution, Participant, TxBuilder};
+pub use crate::pmmr::{MerkleProof, Pmmr};
+pub use crate::pool::TransactionPool;
+pub use crate::protocol::{Message, Response, Sender, Receiver};
+pub use crate::transaction::{Output, OutputFeatures, Transaction};
+pub use crate::utxo::{OutputInfo, UtxoSet};
+#[cfg(feature = "serde")]
+pub use crate::slate::{Slate, VersionedSlate};
+
+pub type Hash = [u8; 32];
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub fn blinding(secp: &Secp256k1, i: u64) -> Result<SecretKey> {
+    let mut sum = ZERO_KEY;
+    for _ in 0..i {
+        sum.add_assign(secp, &ONE_KEY)?;
+    }
+    Ok(sum)
 }
 
-struct TxSignature {
-    partials_sum: SecretKey,
-    nonces_sum: Commitment
+pub fn rand_blinding(secp: &Secp256k1) -> SecretKey {
+    SecretKey::new(secp, &mut thread_rng())
 }
 
-struct Transaction {
-    inputs: Vec<Commitment>,
-    outputs: Vec<Commitment>,
-    signature: TxSignature
+pub fn add_blinding(secp: &Secp256k1, a: &SecretKey, b: &SecretKey) -> Result<SecretKey> {
+    let mut sum = *a;
+    sum.add_assign(secp, b)?;
+    Ok(sum)
 }
 
-fn blinding(secp: &Secp256k1, i: u64) -> SecretKey {
-    let mut sum = ZERO_KEY.clone();
-    for _ in 0..i {
-        sum.add_assign(secp, &ONE_KEY).unwrap();
+// Sum of kernel offsets. Unlike blinding factors they can be zero, which
+// blind_sum rejects: zero terms are dropped, terms on both sides cancel out
+// and nothing left gives ZERO_KEY.
+pub fn offset_sum(
+    secp: &Secp256k1,
+    positive: Vec<SecretKey>,
+    negative: Vec<SecretKey>
+) -> Result<SecretKey> {
+    let mut pos: Vec<SecretKey> = positive.into_iter().filter(|k| *k != ZERO_KEY).collect();
+    let mut neg = vec![];
+    for k in negative.into_iter().filter(|k| *k != ZERO_KEY) {
+        match pos.iter().position(|p| *p == k) {
+            Some(i) => { pos.swap_remove(i); },
+            None => neg.push(k)
+        }
     }
-    sum
+    if pos.is_empty() && neg.is_empty() {
+        return Ok(ZERO_KEY);
+    }
+    Ok(secp.blind_sum(pos, neg)?)
 }
 
-fn rand_blinding(secp: &Secp256k1) -> SecretKey {
-    SecretKey::new(&secp, &mut thread_rng())
-    // blinding(secp, 10)
+pub fn commit(secp: &Secp256k1, value: u64, blinding: &SecretKey) -> Result<Commitment> {
+    Ok(secp.commit(value, *blinding)?)
 }
 
+// Bulletproof that `value` committed with `blinding` is in [0, 2^64).
+pub fn range_proof(secp: &Secp256k1, value: u64, blinding: &SecretKey) -> RangeProof {
+    secp.bullet_proof(
+        value,
+        *blinding,
+        rand_blinding(secp),
+        rand_blinding(secp),
+        None,
+        None
+    )
+}
 
-fn add_blinding(secp: &Secp256k1, a: &SecretKey, b: &SecretKey) -> SecretKey {
-    let mut sum = a.clone();
-    sum.add_assign(secp, b).unwrap();
-    sum
+// Fiat-Shamir challenge of the aggregated Schnorr signature:
+// e = H(nonces_sum | excess | msg)
+pub fn challenge(
+    secp: &Secp256k1,
+    nonces_sum: &Commitment,
+    excess: &Commitment,
+    msg: &[u8]
+) -> Result<SecretKey> {
+    let mut hasher = Sha256::new();
+    hasher.input(&nonces_sum.0[..]);
+    hasher.input(&excess.0[..]);
+    hasher.input(msg);
+    Ok(SecretKey::from_slice(secp, &hasher.result())?)
 }
 
-fn commit(secp: &Secp256k1, value: u64, blinding: &SecretKey) -> Commitment {
-    secp.commit(value, blinding.clone()).unwrap()
+// Partial Schnorr signature: sign = nonce + e * blinding
+pub fn sign_partial(
+    secp: &Secp256k1,
+    blinding: &SecretKey,
+    nonce: &SecretKey,
+    e: &SecretKey
+) -> Result<SecretKey> {
+    let mut sign = *blinding;
+    sign.mul_assign(secp, e)?;
+    sign.add_assign(secp, nonce)?;
+    Ok(sign)
 }
 
+// Check a partial signature against the public nonce and blinding:
+// sign * G == nonce * G + e * blinding * G
+pub fn verify_partial(
+    secp: &Secp256k1,
+    sign: &SecretKey,
+    nonce: &Commitment,
+    blinding: &Commitment,
+    e: &SecretKey
+) -> Result<()> {
+    let sign = commit(secp, 0, sign)?;
+    let left = secp.commit_sum(vec![sign], vec![*nonce])?.to_pubkey(secp)?;
+    let mut right = blinding.to_pubkey(secp)?;
+    right.mul_assign(secp, e)?;
+    if left == right {
+        Ok(())
+    } else {
+        Err(Error::BadPartialSignature)
+    }
+}
 
 #[test]
 fn test_blinding() {
     let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
     secp.randomize(&mut thread_rng());
 
-    let a = blinding(&secp, 6);
-    let b = blinding(&secp, 3);
+    let a = blinding(&secp, 6).unwrap();
+    let b = blinding(&secp, 3).unwrap();
 
-    let expected = blinding(&secp, 9);
-    let sum = add_blinding(&secp, &a, &b);
+    let expected = blinding(&secp, 9).unwrap();
+    let sum = add_blinding(&secp, &a, &b).unwrap();
     assert_eq!(sum, expected);
 }
 
 #[test]
-fn test_transfer() {
+fn test_offset_sum() {
     let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
     secp.randomize(&mut thread_rng());
 
-    // Alice input.
-    let ali_input_blinding = rand_blinding(&secp);
-    let ali_input = commit(&secp, 40, &ali_input_blinding);
-
-    // Ali change output (CO_ali)
-    let ali_change_blinding = rand_blinding(&secp);
-    let ali_change = commit(&secp, 15, &ali_change_blinding);
-
-    // Check
-    {   
-        let sum_blinding = secp.blind_sum(
-            vec![ali_input_blinding.clone(), ali_change_blinding.clone()],
-            vec![]
-        ).unwrap();
-        let sum = commit(&secp, 55, &sum_blinding);
-        assert!(secp.verify_commit_sum(vec![sum], vec![ali_input, ali_change]));
-    }
+    let a = blinding(&secp, 6).unwrap();
+    let b = blinding(&secp, 3).unwrap();
+    let expected = blinding(&secp, 9).unwrap();
+    assert_eq!(offset_sum(&secp, vec![a, ZERO_KEY, b], vec![ZERO_KEY]).unwrap(), expected);
+    assert_eq!(offset_sum(&secp, vec![expected, a], vec![a]).unwrap(), expected);
 
-    // Ali's nonce.
-    let ali_nonce = rand_blinding(&secp);
-    let ali_nonce_commit = commit(&secp, 0, &ali_nonce);
-
-    // Ali's sum of all blinding factors. (rs)
-    let ali_blinding_sum = secp.blind_sum(
-        vec![ali_change_blinding.clone()],
-        vec![ali_input_blinding.clone()]
-    ).unwrap();
-    let ali_blinding_sum_commit = commit(&secp, 0, &ali_blinding_sum);
-
-    // // // Message
-    let msg = Message {
-        amount: 25,
-        input: ali_input,
-        change_output: ali_change,
-        nonce: ali_nonce_commit,
-        sum_of_bliding_factors: ali_blinding_sum_commit 
-    };
-
-    // Bob's part.
-
-    // Secret key.
-    let e: SecretKey = blinding(&secp, 123);
-
-    // Bob's nonce.
-    let bob_nonce = rand_blinding(&secp);
-    let bob_nonce_commit = commit(&secp, 0, &bob_nonce);
-    
-    // Bob's blinding.
-    let bob_blinding = rand_blinding(&secp);
-    let bob_blinding_commit = commit(&secp, 0, &bob_blinding);
-
-    // Bob's signature.
-    let mut bob_sign = bob_blinding.clone();
-    bob_sign.mul_assign(&secp, &e).unwrap();
-    bob_sign.add_assign(&secp, &bob_nonce).unwrap();
-
-    // Bob's output
-    let bob_output = commit(&secp, msg.amount, &bob_blinding);
-
-    // Response
-    let resp = Response {
-        sign: bob_sign,
-        nonce: bob_nonce_commit,
-        blinding: bob_blinding_commit,
-        output: bob_output
-    };
-
-    // Back to Ali.
-
-    // Alice can verify 
-    // sign == bob_nonce + e * bob_blinding
-    // sign * G == bob_nonce * G + e * bob_blinding * G
-    // sign * G == bob_nonce_commit + e * bob_blinding_commit
-    {
-        let sign = commit(&secp, 0, &resp.sign);
-        let left = secp.commit_sum(vec![sign], vec![resp.nonce])
-            .unwrap().to_pubkey(&secp).unwrap();
-        let mut right = resp.blinding.to_pubkey(&secp).unwrap();
-        right.mul_assign(&secp, &e).unwrap();
-
-        assert_eq!(left, right);
-    };
-
-    // Alice singnature
-    let mut ali_sign = ali_blinding_sum.clone();
-    ali_sign.mul_assign(&secp, &e).unwrap();
-    ali_sign.add_assign(&secp, &ali_nonce).unwrap();
-
-    // Sum partial signatures.
-    let mut partials_sum = resp.sign.clone();
-    partials_sum.add_assign(&secp, &ali_sign).unwrap();
-
-    // Sum nonces.
-    let nonces_sum = secp.commit_sum(
-        vec![resp.nonce.clone(), ali_nonce_commit.clone()],
-        vec![]
-    ).unwrap();
-
-    // Transaction Signature
-    let signature = TxSignature { partials_sum, nonces_sum };
-
-    // Transaction
-    let tx = Transaction {
-        inputs: vec![ali_input],
-        outputs: vec![ali_change, resp.output],
-        signature
-    };
-
-    // Kernel
-    let kernel = secp.commit_sum(tx.outputs, tx.inputs).unwrap();
-
-    // Check
-    {
-        let sum_blinding = secp.blind_sum(
-            vec![ali_change_blinding, bob_blinding],
-            vec![ali_input_blinding]
-        ).unwrap();
-        assert_eq!(kernel, commit(&secp, 0, &sum_blinding));
-    }
+    // Zero sums.
+    assert_eq!(offset_sum(&secp, vec![], vec![]).unwrap(), ZERO_KEY);
+    assert_eq!(offset_sum(&secp, vec![ZERO_KEY, ZERO_KEY], vec![]).unwrap(), ZERO_KEY);
+    assert_eq!(offset_sum(&secp, vec![a], vec![a]).unwrap(), ZERO_KEY);
+}
 
-    // Validate tx
-    // tx.signature.partials_sum == tx.signature.nonces_sum + e * kernel
-    {
-        let partials_sum = commit(&secp, 0, &tx.signature.partials_sum);
-        let left = secp.commit_sum(
-            vec![partials_sum],
-            vec![tx.signature.nonces_sum]
-        ).unwrap().to_pubkey(&secp).unwrap();
-        let mut right = kernel.to_pubkey(&secp).unwrap();
-        right.mul_assign(&secp, &e).unwrap();
-
-        assert_eq!(left, right);        
-    }
+#[test]
+fn test_invalid_commitment() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    // Zero value with zero blinding is the point at infinity.
+    assert!(commit(&secp, 0, &ZERO_KEY).is_err());
+}
+
+#[test]
+fn test_challenge() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let nonce = commit(&secp, 0, &rand_blinding(&secp)).unwrap();
+    let excess = commit(&secp, 0, &rand_blinding(&secp)).unwrap();
+    let msg = kernel_message(KernelFeatures::Plain, 0, 0);
+
+    let e = challenge(&secp, &nonce, &excess, &msg).unwrap();
+    assert_eq!(e, challenge(&secp, &nonce, &excess, &msg).unwrap());
+
+    // Every part of the signed data changes the challenge.
+    assert_ne!(e, challenge(&secp, &excess, &nonce, &msg).unwrap());
+    let other_msg = kernel_message(KernelFeatures::Plain, 1, 0);
+    assert_ne!(e, challenge(&secp, &nonce, &excess, &other_msg).unwrap());
+}
+
+#[test]
+fn test_partial_signature() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let blinding = rand_blinding(&secp);
+    let nonce = rand_blinding(&secp);
+    let e = rand_blinding(&secp);
+
+    let sign = sign_partial(&secp, &blinding, &nonce, &e).unwrap();
+    let nonce_commit = commit(&secp, 0, &nonce).unwrap();
+    let blinding_commit = commit(&secp, 0, &blinding).unwrap();
+    assert_eq!(verify_partial(&secp, &sign, &nonce_commit, &blinding_commit, &e), Ok(()));
+
+    let other = rand_blinding(&secp);
+    assert_eq!(
+        verify_partial(&secp, &sign, &nonce_commit, &blinding_commit, &other),
+        Err(Error::BadPartialSignature)
+    );
 }
@@ -0,0 +1,337 @@
+use secp256k1::{
+    Secp256k1, ContextFlag,
+    key::{SecretKey, ZERO_KEY},
+    pedersen::Commitment,
+};
+use rand::thread_rng;
+
+use crate::{
+    commit, rand_blinding, challenge, sign_partial, verify_partial, Error, Result,
+};
+use crate::consensus::{self, ChainParams};
+use crate::kernel::{KernelFeatures, TxSignature, TxKernel, kernel_message};
+use crate::transaction::{Output, Transaction};
+
+// Public part of what a participant brings into a transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contribution {
+    pub inputs: Vec<Commitment>,
+    pub outputs: Vec<Output>,
+    pub nonce: Commitment,
+    // Participant's outputs minus inputs minus offset, times G.
+    pub excess: Commitment,
+    pub kernel_offset: SecretKey
+}
+
+// One of the parties of an N-party transaction.
+pub struct Participant {
+    pub contribution: Contribution,
+    output_blindings: Vec<SecretKey>,
+    excess: SecretKey,
+    nonce: SecretKey
+}
+
+impl Participant {
+    // Spend `inputs` (value and blinding) into new outputs worth
+    // `output_values`. Values don't have to balance per participant, only
+    // for the whole transaction.
+    pub fn new(
+        secp: &Secp256k1,
+        inputs: &[(u64, SecretKey)],
+        output_values: &[u64]
+    ) -> Result<Participant> {
+        let output_blindings: Vec<SecretKey> = output_values.iter()
+            .map(|_| rand_blinding(secp))
+            .collect();
+        let mut outputs = vec![];
+        for (value, blinding) in output_values.iter().zip(&output_blindings) {
+            outputs.push(Output::new(secp, *value, blinding)?);
+        }
+        let mut input_commits = vec![];
+        for (value, blinding) in inputs {
+            input_commits.push(commit(secp, *value, blinding)?);
+        }
+
+        let kernel_offset = rand_blinding(secp);
+        let mut negative: Vec<SecretKey> = inputs.iter().map(|i| i.1).collect();
+        negative.push(kernel_offset);
+        let excess = secp.blind_sum(output_blindings.clone(), negative)?;
+        let nonce = rand_blinding(secp);
+
+        let contribution = Contribution {
+            inputs: input_commits,
+            outputs,
+            nonce: commit(secp, 0, &nonce)?,
+            excess: commit(secp, 0, &excess)?,
+            kernel_offset
+        };
+
+        Ok(Participant { contribution, output_blindings, excess, nonce })
+    }
+
+    pub fn output_blindings(&self) -> &[SecretKey] {
+        &self.output_blindings
+    }
+
+    // Second round: check the collected transaction and sign our part.
+    // Consumes the participant, as signing another transaction with the same
+    // nonce would leak the excess; keep the output blindings before signing.
+    pub fn sign(self, secp: &Secp256k1, builder: &TxBuilder) -> Result<SecretKey> {
+        if !builder.contributions.contains(&self.contribution) {
+            return Err(Error::MissingContribution);
+        }
+        builder.verify(secp)?;
+        let e = builder.challenge(secp)?;
+        sign_partial(secp, &self.excess, &self.nonce, &e)
+    }
+}
+
+// Collects contributions of all participants (first round), then their
+// partial signatures (second round) into one transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxBuilder {
+    pub fee: u64,
+    pub features: KernelFeatures,
+    // Absolute or relative lock height, depending on the features.
+    pub lock_height: u64,
+    pub contributions: Vec<Contribution>
+}
+
+impl TxBuilder {
+    pub fn new(fee: u64) -> TxBuilder {
+        TxBuilder {
+            fee,
+            features: KernelFeatures::Plain,
+            lock_height: 0,
+            contributions: vec![]
+        }
+    }
+
+    // Lock the transaction until the chain reaches `lock_height`.
+    pub fn with_lock_height(mut self, lock_height: u64) -> TxBuilder {
+        self.features = KernelFeatures::for_lock_height(lock_height);
+        self.lock_height = lock_height;
+        self
+    }
+
+    // Lock the transaction until `relative_height` blocks after any other
+    // NRD kernel with the same excess.
+    pub fn with_relative_height(mut self, relative_height: u64) -> TxBuilder {
+        self.features = KernelFeatures::NoRecentDuplicate;
+        self.lock_height = relative_height;
+        self
+    }
+
+    pub fn add(&mut self, contribution: Contribution) {
+        self.contributions.push(contribution);
+    }
+
+    // Lowest fee relayed for the transaction of the contributions collected
+    // so far, with its single kernel.
+    pub fn min_fee(&self, params: &ChainParams) -> u64 {
+        let inputs = self.contributions.iter().map(|c| c.inputs.len()).sum();
+        let outputs = self.contributions.iter().map(|c| c.outputs.len()).sum();
+        params.min_fee(consensus::weight(inputs, outputs, 1))
+    }
+
+    pub fn kernel_message(&self) -> Vec<u8> {
+        kernel_message(self.features, self.fee, self.lock_height)
+    }
+
+    pub fn nonces_sum(&self, secp: &Secp256k1) -> Result<Commitment> {
+        let nonces = self.contributions.iter().map(|c| c.nonce).collect();
+        Ok(secp.commit_sum(nonces, vec![])?)
+    }
+
+    pub fn excess(&self, secp: &Secp256k1) -> Result<Commitment> {
+        let excesses = self.contributions.iter().map(|c| c.excess).collect();
+        Ok(secp.commit_sum(excesses, vec![])?)
+    }
+
+    pub fn kernel_offset(&self, secp: &Secp256k1) -> Result<SecretKey> {
+        let offsets = self.contributions.iter().map(|c| c.kernel_offset).collect();
+        Ok(secp.blind_sum(offsets, vec![])?)
+    }
+
+    pub fn challenge(&self, secp: &Secp256k1) -> Result<SecretKey> {
+        challenge(
+            secp,
+            &self.nonces_sum(secp)?,
+            &self.excess(secp)?,
+            &self.kernel_message()
+        )
+    }
+
+    // Check range proofs and that all contributions together balance:
+    // outputs + fee * H - inputs == excess + kernel_offset * G
+    pub fn verify(&self, secp: &Secp256k1) -> Result<()> {
+        if self.contributions.is_empty() {
+            return Err(Error::EmptyTransaction);
+        }
+        let mut positive = vec![];
+        let mut negative = vec![self.excess(secp)?];
+        for c in &self.contributions {
+            for output in &c.outputs {
+                output.verify(secp)?;
+                positive.push(output.commit);
+            }
+            negative.extend_from_slice(&c.inputs);
+        }
+        if self.fee != 0 {
+            positive.push(secp.commit_value(self.fee)?);
+        }
+        let kernel_offset = self.kernel_offset(secp)?;
+        if kernel_offset != ZERO_KEY {
+            negative.push(secp.commit(0, kernel_offset)?);
+        }
+        if secp.verify_commit_sum(positive, negative) {
+            Ok(())
+        } else {
+            Err(Error::UnbalancedTransaction)
+        }
+    }
+
+    // Last round: check every partial signature (in the order of the
+    // contributions) and aggregate them into the final transaction.
+    pub fn finalize(&self, secp: &Secp256k1, partials: &[SecretKey]) -> Result<Transaction> {
+        self.verify(secp)?;
+        if partials.len() != self.contributions.len() {
+            return Err(Error::BadPartialSignature);
+        }
+        let e = self.challenge(secp)?;
+        for (c, sign) in self.contributions.iter().zip(partials) {
+            verify_partial(secp, sign, &c.nonce, &c.excess, &e)?;
+        }
+
+        let kernel = TxKernel {
+            features: self.features,
+            fee: self.fee,
+            lock_height: self.lock_height,
+            excess: self.excess(secp)?,
+            excess_sig: TxSignature {
+                partials_sum: secp.blind_sum(partials.to_vec(), vec![])?,
+                nonces_sum: self.nonces_sum(secp)?
+            }
+        };
+
+        let tx = Transaction {
+            inputs: self.contributions.iter()
+                .flat_map(|c| c.inputs.iter().cloned())
+                .collect(),
+            outputs: self.contributions.iter()
+                .flat_map(|c| c.outputs.iter().cloned())
+                .collect(),
+            kernels: vec![kernel],
+            kernel_offset: self.kernel_offset(secp)?
+        };
+        tx.validate(secp)?;
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+fn run(
+    secp: &Secp256k1,
+    builder: &mut TxBuilder,
+    participants: Vec<Participant>
+) -> Result<Transaction> {
+    for p in &participants {
+        builder.add(p.contribution.clone());
+    }
+    let mut partials = vec![];
+    for p in participants {
+        partials.push(p.sign(secp, builder)?);
+    }
+    builder.finalize(secp, &partials)
+}
+
+#[test]
+fn test_several_payers() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    // Alice and Carol fund a payment of 50 to Bob, each paying half of the fee.
+    let alice = Participant::new(&secp, &[(40, rand_blinding(&secp))], &[14]).unwrap();
+    let carol = Participant::new(
+        &secp,
+        &[(20, rand_blinding(&secp)), (10, rand_blinding(&secp))],
+        &[4]
+    ).unwrap();
+    let bob = Participant::new(&secp, &[], &[50]).unwrap();
+
+    let tx = run(&secp, &mut TxBuilder::new(2), vec![alice, carol, bob]).unwrap();
+    assert_eq!(tx.inputs.len(), 3);
+    assert_eq!(tx.outputs.len(), 3);
+    assert_eq!(tx.kernels[0].fee, 2);
+    assert_eq!(tx.validate(&secp), Ok(()));
+}
+
+#[test]
+fn test_several_recipients() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    // Signing consumes the participants, so each transaction gets new ones.
+    let participants = || vec![
+        Participant::new(&secp, &[(100, rand_blinding(&secp))], &[9]).unwrap(),
+        Participant::new(&secp, &[], &[30]).unwrap(),
+        Participant::new(&secp, &[], &[60]).unwrap(),
+    ];
+
+    let tx = run(&secp, &mut TxBuilder::new(1), participants()).unwrap();
+    assert_eq!(tx.validate(&secp), Ok(()));
+
+    // Same, locked until height 10.
+    let mut builder = TxBuilder::new(1).with_lock_height(10);
+    let tx = run(&secp, &mut builder, participants()).unwrap();
+    assert_eq!(tx.kernels[0].features, KernelFeatures::HeightLocked);
+    assert_eq!(tx.lock_height(), 10);
+    assert_eq!(tx.validate(&secp), Ok(()));
+    let params = ChainParams::default();
+    assert_eq!(builder.min_fee(&params), params.min_fee(tx.weight()));
+
+    // NRD kernels aren't locked to an absolute height.
+    let mut builder = TxBuilder::new(1).with_relative_height(10);
+    let tx = run(&secp, &mut builder, participants()).unwrap();
+    assert_eq!(tx.kernels[0].features, KernelFeatures::NoRecentDuplicate);
+    assert_eq!(tx.kernels[0].lock_height, 10);
+    assert_eq!(tx.lock_height(), 0);
+    assert_eq!(tx.validate(&secp), Ok(()));
+}
+
+#[test]
+fn test_multiparty_rejects() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    // Outputs worth more than the inputs.
+    let alice = Participant::new(&secp, &[(40, rand_blinding(&secp))], &[15]).unwrap();
+    let bob = Participant::new(&secp, &[], &[25]).unwrap();
+    assert_eq!(
+        run(&secp, &mut TxBuilder::new(1), vec![alice, bob]).err(),
+        Some(Error::UnbalancedTransaction)
+    );
+
+    let alice = Participant::new(&secp, &[(40, rand_blinding(&secp))], &[15]).unwrap();
+    let bob = Participant::new(&secp, &[], &[25]).unwrap();
+    let mut builder = TxBuilder::new(0);
+    builder.add(alice.contribution.clone());
+    builder.add(bob.contribution.clone());
+
+    // Participant missing from the transaction won't sign it.
+    let carol = Participant::new(&secp, &[], &[1]).unwrap();
+    assert_eq!(carol.sign(&secp, &builder).err(), Some(Error::MissingContribution));
+
+    // Bad or missing partial signature.
+    let alice_sign = alice.sign(&secp, &builder).unwrap();
+    let bob_sign = bob.sign(&secp, &builder).unwrap();
+    assert_eq!(
+        builder.finalize(&secp, &[alice_sign, rand_blinding(&secp)]).err(),
+        Some(Error::BadPartialSignature)
+    );
+    assert_eq!(
+        builder.finalize(&secp, &[alice_sign]).err(),
+        Some(Error::BadPartialSignature)
+    );
+    assert!(builder.finalize(&secp, &[alice_sign, bob_sign]).is_ok());
+}
@@ -0,0 +1,347 @@
+use sha2::{Sha256, Digest};
+
+use crate::Hash;
+
+// Nodes are addressed by their 0-based position in post-order, so leaves
+// are at positions 0, 1, 3, 4, 7, 8, 10, 11, 15...
+
+// Height of the node at `pos`, leaves are at height 0.
+pub fn height(pos: u64) -> u64 {
+    // Jump to the left sibling until we reach the leftmost node of its
+    // height, whose 1-based position is all ones.
+    let mut p = pos + 1;
+    while p & (p + 1) != 0 {
+        p -= (1 << (63 - p.leading_zeros())) - 1;
+    }
+    63 - p.leading_zeros() as u64
+}
+
+pub fn is_leaf(pos: u64) -> bool {
+    height(pos) == 0
+}
+
+// Positions of the peaks of an MMR with `size` nodes, left to right.
+// Empty if no MMR has that size.
+pub fn peaks(size: u64) -> Vec<u64> {
+    if size == 0 {
+        return vec![];
+    }
+    let mut peak_size = u64::MAX >> size.leading_zeros();
+    let mut peaks = vec![];
+    let mut done = 0;
+    while peak_size != 0 {
+        if size - done >= peak_size {
+            done += peak_size;
+            peaks.push(done - 1);
+        }
+        peak_size >>= 1;
+    }
+    if done == size { peaks } else { vec![] }
+}
+
+fn to_hash(hasher: Sha256) -> Hash {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&hasher.result());
+    hash
+}
+
+fn hash_leaf(pos: u64, data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.input(pos.to_be_bytes());
+    hasher.input(data);
+    to_hash(hasher)
+}
+
+fn hash_node(pos: u64, left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.input(pos.to_be_bytes());
+    hasher.input(left);
+    hasher.input(right);
+    to_hash(hasher)
+}
+
+// Peaks hashed together right to left.
+fn bag(size: u64, peaks: &[Hash]) -> Hash {
+    let mut iter = peaks.iter().rev();
+    match iter.next() {
+        Some(last) => iter.fold(*last, |acc, peak| hash_node(size, peak, &acc)),
+        None => [0; 32]
+    }
+}
+
+// Positions of the sibling and parent of the node at `pos` and `height`.
+fn family(pos: u64, height: u64) -> (u64, u64) {
+    if self::height(pos + 1) > height {
+        (pos + 1 - (2 << height), pos + 1)
+    } else {
+        (pos + (2 << height) - 1, pos + (2 << height))
+    }
+}
+
+// Append-only Merkle Mountain Range. Spent leaves are only marked in the
+// prune bitmap, their hashes are kept so the roots don't change.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pmmr {
+    hashes: Vec<Hash>,
+    pruned: Vec<u8>
+}
+
+impl Pmmr {
+    pub fn new() -> Pmmr {
+        Pmmr::default()
+    }
+
+    // Number of nodes.
+    pub fn size(&self) -> u64 {
+        self.hashes.len() as u64
+    }
+
+    // Add a leaf and the parents it completes, returning its position.
+    pub fn append(&mut self, data: &[u8]) -> u64 {
+        let leaf_pos = self.size();
+        self.hashes.push(hash_leaf(leaf_pos, data));
+        let mut height = 0;
+        while self::height(self.size()) > height {
+            let pos = self.size() - 1;
+            let (left, parent) = family(pos, height);
+            let hash = hash_node(parent, &self.hashes[left as usize], &self.hashes[pos as usize]);
+            self.hashes.push(hash);
+            height += 1;
+        }
+        leaf_pos
+    }
+
+    pub fn root(&self) -> Hash {
+        let peaks: Vec<Hash> = peaks(self.size()).iter()
+            .map(|&pos| self.hashes[pos as usize])
+            .collect();
+        bag(self.size(), &peaks)
+    }
+
+    // Mark the leaf at `pos` as spent.
+    pub fn prune(&mut self, pos: u64) {
+        if pos < self.size() && is_leaf(pos) {
+            let byte = (pos / 8) as usize;
+            if self.pruned.len() <= byte {
+                self.pruned.resize(byte + 1, 0);
+            }
+            self.pruned[byte] |= 1 << (pos % 8);
+        }
+    }
+
+    // Mark the leaf at `pos` as unspent again.
+    pub fn unprune(&mut self, pos: u64) {
+        if let Some(b) = self.pruned.get_mut((pos / 8) as usize) {
+            *b &= !(1 << (pos % 8));
+        }
+        self.trim_pruned();
+    }
+
+    // Drop trailing empty bytes, so equal MMRs have equal bitmaps.
+    fn trim_pruned(&mut self) {
+        while self.pruned.last() == Some(&0) {
+            self.pruned.pop();
+        }
+    }
+
+    pub fn is_pruned(&self, pos: u64) -> bool {
+        self.pruned.get((pos / 8) as usize)
+            .map(|b| b & (1 << (pos % 8)) != 0)
+            .unwrap_or(false)
+    }
+
+    // Number of leaves not marked as spent.
+    pub fn unpruned_leaves(&self) -> u64 {
+        (0..self.size()).filter(|&pos| is_leaf(pos) && !self.is_pruned(pos)).count() as u64
+    }
+
+    // Whether the leaf at `pos` was appended with `data`.
+    pub fn has_leaf(&self, pos: u64, data: &[u8]) -> bool {
+        pos < self.size() && is_leaf(pos) && self.hashes[pos as usize] == hash_leaf(pos, data)
+    }
+
+    // Drop everything appended after the MMR had `size` nodes.
+    pub fn rewind(&mut self, size: u64) {
+        self.hashes.truncate(size as usize);
+        for pos in size..(self.pruned.len() as u64 * 8) {
+            self.pruned[(pos / 8) as usize] &= !(1 << (pos % 8));
+        }
+        self.trim_pruned();
+    }
+
+    // Proof that the unspent leaf at `pos` is included under the root.
+    pub fn proof(&self, pos: u64) -> Option<MerkleProof> {
+        if pos >= self.size() || !is_leaf(pos) || self.is_pruned(pos) {
+            return None;
+        }
+        let peak_positions = peaks(self.size());
+        let mut path = vec![];
+        let mut node = pos;
+        let mut height = 0;
+        while !peak_positions.contains(&node) {
+            let (sibling, parent) = family(node, height);
+            path.push(self.hashes[sibling as usize]);
+            node = parent;
+            height += 1;
+        }
+        Some(MerkleProof {
+            mmr_size: self.size(),
+            pos,
+            path,
+            peaks: peak_positions.iter().map(|&p| self.hashes[p as usize]).collect()
+        })
+    }
+}
+
+// Largest MMR size proofs are checked against, so that positions and their
+// parents always fit in a u64.
+const MAX_PROOF_MMR_SIZE: u64 = u64::MAX >> 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    pub mmr_size: u64,
+    // Position of the leaf.
+    pub pos: u64,
+    // Siblings from the leaf up to its peak.
+    pub path: Vec<Hash>,
+    pub peaks: Vec<Hash>
+}
+
+impl MerkleProof {
+    // Check that `data` is the leaf at `pos` of the MMR with `root`.
+    // Proofs come from untrusted peers: anything malformed is rejected.
+    pub fn verify(&self, root: &Hash, data: &[u8]) -> bool {
+        if self.mmr_size > MAX_PROOF_MMR_SIZE
+            || self.path.len() >= 64
+            || self.pos >= self.mmr_size
+            || !is_leaf(self.pos)
+        {
+            return false;
+        }
+        let peak_positions = peaks(self.mmr_size);
+        if peak_positions.len() != self.peaks.len() {
+            return false;
+        }
+        let mut node = self.pos;
+        let mut hash = hash_leaf(node, data);
+        for (height, sibling) in self.path.iter().enumerate() {
+            let height = height as u64;
+            if node >= self.mmr_size || self::height(node) != height {
+                return false;
+            }
+            let (sibling_pos, parent) = family(node, height);
+            hash = if sibling_pos < node {
+                hash_node(parent, sibling, &hash)
+            } else {
+                hash_node(parent, &hash, sibling)
+            };
+            node = parent;
+        }
+        match peak_positions.iter().position(|&p| p == node) {
+            Some(i) => self.peaks[i] == hash && bag(self.mmr_size, &self.peaks) == *root,
+            None => false
+        }
+    }
+}
+
+#[test]
+fn test_positions() {
+    let heights: Vec<u64> = (0..11).map(height).collect();
+    assert_eq!(heights, vec![0, 0, 1, 0, 0, 1, 2, 0, 0, 1, 0]);
+    assert_eq!(peaks(1), vec![0]);
+    assert_eq!(peaks(3), vec![2]);
+    assert_eq!(peaks(4), vec![2, 3]);
+    assert_eq!(peaks(11), vec![6, 9, 10]);
+    assert!(peaks(5).is_empty());
+    assert!(peaks(0).is_empty());
+}
+
+#[test]
+fn test_pmmr() {
+    let mut pmmr = Pmmr::new();
+    assert_eq!(pmmr.root(), [0; 32]);
+
+    let mut roots = vec![];
+    let mut leaves = vec![];
+    for i in 0..11u8 {
+        leaves.push(pmmr.append(&[i]));
+        assert!(!peaks(pmmr.size()).is_empty());
+        assert!(!roots.contains(&pmmr.root()));
+        roots.push(pmmr.root());
+    }
+    assert_eq!(leaves[..6], [0, 1, 3, 4, 7, 8]);
+    assert_eq!(pmmr.size(), 19);
+
+    let root = pmmr.root();
+    for (i, &pos) in leaves.iter().enumerate() {
+        let proof = pmmr.proof(pos).unwrap();
+        assert!(proof.verify(&root, &[i as u8]));
+        assert!(!proof.verify(&root, &[i as u8 + 1]));
+        assert!(!proof.verify(&roots[5], &[i as u8]));
+    }
+    assert_eq!(pmmr.proof(2), None);
+    assert_eq!(pmmr.proof(19), None);
+
+    // Proof moved to another leaf.
+    let mut proof = pmmr.proof(0).unwrap();
+    proof.pos = 1;
+    assert!(!proof.verify(&root, &[0]));
+
+    // Malformed proofs are rejected without overflowing.
+    let mut proof = pmmr.proof(0).unwrap();
+    proof.path = vec![[0; 32]; 64];
+    assert!(!proof.verify(&root, &[0]));
+    proof.path = vec![[0; 32]; 63];
+    assert!(!proof.verify(&root, &[0]));
+    let mut proof = pmmr.proof(0).unwrap();
+    proof.mmr_size = u64::MAX;
+    proof.pos = u64::MAX - 1;
+    assert!(!proof.verify(&root, &[0]));
+    proof.mmr_size = MAX_PROOF_MMR_SIZE;
+    proof.pos = MAX_PROOF_MMR_SIZE - 1;
+    assert!(!proof.verify(&root, &[0]));
+
+    // Rewinding restores older roots.
+    pmmr.rewind(10);
+    assert_eq!(pmmr.root(), roots[5]);
+    pmmr.append(&[6]);
+    assert_eq!(pmmr.root(), roots[6]);
+}
+
+#[test]
+fn test_prune() {
+    let mut pmmr = Pmmr::new();
+    for i in 0..5u8 {
+        pmmr.append(&[i]);
+    }
+    let root = pmmr.root();
+
+    assert_eq!(pmmr.unpruned_leaves(), 5);
+    assert!(pmmr.has_leaf(3, &[2]));
+    assert!(!pmmr.has_leaf(3, &[3]));
+    assert!(!pmmr.has_leaf(2, &[2]));
+
+    pmmr.prune(3);
+    assert!(pmmr.is_pruned(3));
+    assert_eq!(pmmr.unpruned_leaves(), 4);
+    assert!(!pmmr.is_pruned(4));
+    assert_eq!(pmmr.proof(3), None);
+    assert!(pmmr.proof(4).unwrap().verify(&root, &[3]));
+    assert_eq!(pmmr.root(), root);
+
+    // Only leaves are pruned.
+    pmmr.prune(2);
+    assert!(!pmmr.is_pruned(2));
+
+    pmmr.unprune(3);
+    assert!(!pmmr.is_pruned(3));
+    assert!(pmmr.proof(3).unwrap().verify(&root, &[2]));
+
+    // Rewinding drops pruning beyond the new size.
+    let before = pmmr.clone();
+    pmmr.prune(7);
+    pmmr.rewind(7);
+    assert!(!pmmr.is_pruned(7));
+    pmmr.append(&[4]);
+    assert_eq!(pmmr, before);
+}
@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+
+use secp256k1::{
+    Secp256k1, ContextFlag,
+    pedersen::Commitment,
+};
+use rand::thread_rng;
+
+use crate::{rand_blinding, Error, Result};
+use crate::chain::ChainState;
+use crate::transaction::Transaction;
+
+// Valid transactions waiting to be included in a block. Every pool
+// transaction spends outputs of the chain, and no two spend the same one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransactionPool {
+    txs: Vec<Transaction>
+}
+
+impl TransactionPool {
+    pub fn new() -> TransactionPool {
+        TransactionPool::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    pub fn txs(&self) -> &[Transaction] {
+        &self.txs
+    }
+
+    // Validate a transaction against the chain and the pool and add it.
+    pub fn add(&mut self, secp: &Secp256k1, chain: &ChainState, tx: Transaction) -> Result<()> {
+        tx.validate(secp)?;
+        if tx.fee()? < chain.params().min_fee(tx.weight()) {
+            return Err(Error::FeeTooLow);
+        }
+        self.check(chain, &tx)?;
+        self.txs.push(tx);
+        Ok(())
+    }
+
+    // Check a transaction against the chain's unspent outputs and the
+    // transactions already in the pool.
+    fn check(&self, chain: &ChainState, tx: &Transaction) -> Result<()> {
+        let height = chain.tip().height + 1;
+        if tx.lock_height() > height {
+            return Err(Error::LockedKernel);
+        }
+        chain.utxos().validate_tx(chain.params(), tx, height)?;
+        chain.check_nrd(&tx.kernels, height)?;
+        let nrd_excesses: HashSet<&Commitment> = self.txs.iter()
+            .flat_map(|t| t.kernels.iter().filter(|k| k.is_nrd()).map(|k| &k.excess))
+            .collect();
+        if tx.kernels.iter().any(|k| k.is_nrd() && nrd_excesses.contains(&k.excess)) {
+            return Err(Error::RecentDuplicateKernel);
+        }
+        let spent: HashSet<&Commitment> = self.txs.iter().flat_map(|t| &t.inputs).collect();
+        if tx.inputs.iter().any(|input| spent.contains(input)) {
+            return Err(Error::DoubleSpend);
+        }
+        let created: HashSet<&Commitment> = self.txs.iter()
+            .flat_map(|t| t.outputs.iter().map(|o| &o.commit))
+            .collect();
+        if tx.outputs.iter().any(|o| created.contains(&o.commit)) {
+            return Err(Error::DuplicateOutput);
+        }
+        Ok(())
+    }
+
+    // Drop transactions that no longer apply on top of the chain, e.g.
+    // after a new block included them or spent the same outputs.
+    pub fn reconcile(&mut self, chain: &ChainState) {
+        let txs = std::mem::take(&mut self.txs);
+        for tx in txs {
+            if self.check(chain, &tx).is_ok() {
+                self.txs.push(tx);
+            }
+        }
+    }
+
+    // Up to `max` transactions for the next block, highest fee per weight
+    // first.
+    pub fn select(&self, max: usize) -> Vec<Transaction> {
+        let mut txs = self.txs.clone();
+        // Pool transactions were validated, so their fees don't overflow.
+        let fee = |tx: &Transaction| tx.fee().unwrap_or(0) as u128;
+        // a.fee / a.weight > b.fee / b.weight without rounding.
+        txs.sort_by(|a, b| {
+            let a_rate = fee(a) * b.weight() as u128;
+            let b_rate = fee(b) * a.weight() as u128;
+            b_rate.cmp(&a_rate)
+        });
+        txs.truncate(max);
+        txs
+    }
+
+    // All pool transactions as one.
+    pub fn aggregate(&self, secp: &Secp256k1) -> Result<Transaction> {
+        Transaction::aggregate(secp, self.txs.clone())
+    }
+}
+
+#[test]
+fn test_pool() {
+    use crate::block::transfer;
+    use crate::consensus::ChainParams;
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+    let params = ChainParams {
+        coinbase_maturity: 0,
+        min_fee_rate: 0,
+        ..ChainParams::default()
+    };
+    let reward = params.reward(0);
+
+    // Three coinbase outputs to spend.
+    let blindings = [rand_blinding(&secp), rand_blinding(&secp), rand_blinding(&secp)];
+    let mut chain = ChainState::new(&secp, params, &blindings[0], 0).unwrap();
+    for (timestamp, blinding) in blindings[1..].iter().enumerate() {
+        let block = chain.build_block(&secp, vec![], blinding, timestamp as u64 + 1).unwrap();
+        chain.process_block(&secp, &block).unwrap();
+    }
+
+    let mut pool = TransactionPool::new();
+    let tx1 = transfer(&secp, reward, &blindings[0], 1_000, 2);
+    let tx2 = transfer(&secp, reward, &blindings[1], 1_000, 5);
+    let tx3 = transfer(&secp, reward, &blindings[2], 1_000, 1);
+    assert_eq!(pool.add(&secp, &chain, tx1.clone()), Ok(()));
+    assert_eq!(pool.add(&secp, &chain, tx2.clone()), Ok(()));
+    assert_eq!(pool.add(&secp, &chain, tx3.clone()), Ok(()));
+
+    // Conflicting, unknown, repeated and invalid transactions.
+    let conflict = transfer(&secp, reward, &blindings[0], 2_000, 9);
+    assert_eq!(pool.add(&secp, &chain, conflict.clone()), Err(Error::DoubleSpend));
+    let unknown = transfer(&secp, reward, &rand_blinding(&secp), 2_000, 9);
+    assert_eq!(pool.add(&secp, &chain, unknown), Err(Error::MissingInput));
+    assert_eq!(pool.add(&secp, &chain, tx1.clone()), Err(Error::DoubleSpend));
+    let mut invalid = conflict.clone();
+    invalid.kernels[0].fee += 1;
+    assert_eq!(pool.add(&secp, &chain, invalid), Err(Error::UnbalancedTransaction));
+    assert_eq!(pool.len(), 3);
+
+    let tx = pool.aggregate(&secp).unwrap();
+    assert_eq!(tx.validate(&secp), Ok(()));
+    assert_eq!(tx.kernels.len(), 3);
+
+    // Highest fees first.
+    assert_eq!(pool.select(2), vec![tx2.clone(), tx1.clone()]);
+    assert_eq!(pool.select(10).len(), 3);
+
+    // A block with tx2 and a conflicting spend of tx1's input evicts both.
+    let block = chain.build_block(&secp, vec![tx2, conflict], &rand_blinding(&secp), 3).unwrap();
+    chain.process_block(&secp, &block).unwrap();
+    pool.reconcile(&chain);
+    assert_eq!(pool.txs(), &[tx3]);
+
+    let block = chain.build_block(&secp, pool.select(10), &rand_blinding(&secp), 4).unwrap();
+    assert_eq!(chain.process_block(&secp, &block), Ok(()));
+    pool.reconcile(&chain);
+    assert!(pool.is_empty());
+    assert_eq!(pool.aggregate(&secp), Err(Error::EmptyTransaction));
+}
+
+#[test]
+fn test_pool_fee_rate() {
+    use crate::block::transfer;
+    use crate::consensus::ChainParams;
+    use crate::transaction::single_signer_tx;
+    use crate::Sender;
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+    let params = ChainParams {
+        coinbase_maturity: 0,
+        min_fee_rate: 10,
+        ..ChainParams::default()
+    };
+    let reward = params.reward(0);
+    let fee = Sender::min_fee(&params);
+
+    let blindings = [rand_blinding(&secp), rand_blinding(&secp)];
+    let mut chain = ChainState::new(&secp, params, &blindings[0], 0).unwrap();
+    let block = chain.build_block(&secp, vec![], &blindings[1], 1).unwrap();
+    chain.process_block(&secp, &block).unwrap();
+
+    let mut pool = TransactionPool::new();
+    let cheap = transfer(&secp, reward, &blindings[0], 1_000, fee - 1);
+    assert_eq!(pool.add(&secp, &chain, cheap), Err(Error::FeeTooLow));
+    let tx1 = transfer(&secp, reward, &blindings[0], 1_000, 600);
+    assert_eq!(pool.add(&secp, &chain, tx1.clone()), Ok(()));
+
+    // Higher fee, but an extra output makes it cheaper per weight.
+    let outputs = vec![
+        (1_000, rand_blinding(&secp)),
+        (2_000, rand_blinding(&secp)),
+        (reward - 3_800, rand_blinding(&secp))
+    ];
+    let tx2 = single_signer_tx(&secp, (reward, blindings[1]), outputs, 800, rand_blinding(&secp));
+    assert!(tx2.fee().unwrap() > tx1.fee().unwrap());
+    assert_eq!(pool.add(&secp, &chain, tx2.clone()), Ok(()));
+    assert_eq!(pool.select(2), vec![tx1, tx2]);
+}
+
+#[test]
+fn test_pool_lock_height() {
+    use crate::consensus::ChainParams;
+    use crate::{Sender, Receiver};
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+    let params = ChainParams {
+        coinbase_maturity: 0,
+        min_fee_rate: 0,
+        ..ChainParams::default()
+    };
+    let reward = params.reward(0);
+
+    let genesis_blinding = rand_blinding(&secp);
+    let mut chain = ChainState::new(&secp, params, &genesis_blinding, 0).unwrap();
+
+    // Locked until height 2, while the next block is at height 1.
+    let sender = Sender::initiate(&secp, reward, &genesis_blinding, 1_000, 2)
+        .unwrap()
+        .with_lock_height(2);
+    let receiver = Receiver::respond(&secp, &sender.message).unwrap();
+    let tx = sender.finalize(&secp, &receiver.response).unwrap();
+
+    let mut pool = TransactionPool::new();
+    assert_eq!(pool.add(&secp, &chain, tx.clone()), Err(Error::LockedKernel));
+
+    let block = chain.build_block(&secp, vec![], &rand_blinding(&secp), 1).unwrap();
+    chain.process_block(&secp, &block).unwrap();
+    assert_eq!(pool.add(&secp, &chain, tx), Ok(()));
+    let block = chain.build_block(&secp, pool.select(10), &rand_blinding(&secp), 2).unwrap();
+    assert_eq!(chain.process_block(&secp, &block), Ok(()));
+}
+
+#[test]
+fn test_pool_nrd() {
+    use crate::chain::nrd_tx;
+    use crate::consensus::ChainParams;
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+    let params = ChainParams {
+        coinbase_maturity: 0,
+        min_fee_rate: 0,
+        ..ChainParams::default()
+    };
+    let reward = params.reward(0);
+
+    let blindings = [rand_blinding(&secp), rand_blinding(&secp)];
+    let mut chain = ChainState::new(&secp, params, &blindings[0], 0).unwrap();
+    let block = chain.build_block(&secp, vec![], &blindings[1], 1).unwrap();
+    chain.process_block(&secp, &block).unwrap();
+
+    // Two transactions with the same NRD excess can't wait in the pool together.
+    let excess = rand_blinding(&secp);
+    let tx1 = nrd_tx(&secp, (reward, blindings[0]), 2, 1, &excess);
+    let tx2 = nrd_tx(&secp, (reward, blindings[1]), 2, 1, &excess);
+    let mut pool = TransactionPool::new();
+    assert_eq!(pool.add(&secp, &chain, tx1), Ok(()));
+    assert_eq!(pool.add(&secp, &chain, tx2.clone()), Err(Error::RecentDuplicateKernel));
+
+    // Once the first one is mined, the second one is valid in the next block.
+    let block = chain.build_block(&secp, pool.select(10), &rand_blinding(&secp), 2).unwrap();
+    chain.process_block(&secp, &block).unwrap();
+    pool.reconcile(&chain);
+    assert_eq!(pool.add(&secp, &chain, tx2), Ok(()));
+}
@@ -0,0 +1,595 @@
+use secp256k1::{
+    Secp256k1, ContextFlag,
+    key::{SecretKey, ZERO_KEY},
+    pedersen::Commitment,
+};
+use rand::thread_rng;
+
+use crate::{
+    commit, rand_blinding, challenge, kernel_message, sign_partial,
+    verify_partial, Error, Result,
+};
+use crate::consensus::{self, ChainParams};
+use crate::kernel::{KernelFeatures, TxSignature, TxKernel};
+use crate::transaction::{Output, Transaction};
+use crate::ser::{Writeable, Readable, Writer, Reader};
+
+// Sent by the sender to the receiver to start a transfer.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Message {
+    pub amount: u64,
+    pub fee: u64,
+    // Lowest height of a block the transaction can be included in, 0 for
+    // no lock.
+    pub lock_height: u64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::slate::commitment_hex"))]
+    pub input: Commitment,
+    pub change_output: Output,
+    #[cfg_attr(feature = "serde", serde(with = "crate::slate::commitment_hex"))]
+    pub nonce: Commitment,
+    #[cfg_attr(feature = "serde", serde(with = "crate::slate::commitment_hex"))]
+    pub sum_of_bliding_factors: Commitment,
+    #[cfg_attr(feature = "serde", serde(with = "crate::slate::kernel_offset_hex"))]
+    pub kernel_offset: SecretKey
+}
+
+impl Message {
+    // Check what the sender sent before signing anything:
+    // change_output - input + (amount + fee) * H == sum_of_bliding_factors + kernel_offset * G
+    // and that the change output has a valid range proof.
+    pub fn verify(&self, secp: &Secp256k1) -> Result<()> {
+        if self.amount == 0 {
+            return Err(Error::AmountMismatch);
+        }
+        let spent = self.amount.checked_add(self.fee).ok_or(Error::AmountMismatch)?;
+        self.change_output.verify(secp)?;
+
+        let positive = vec![self.change_output.commit, secp.commit_value(spent)?];
+        let mut negative = vec![self.input, self.sum_of_bliding_factors];
+        if self.kernel_offset != ZERO_KEY {
+            negative.push(secp.commit(0, self.kernel_offset)?);
+        }
+        if secp.verify_commit_sum(positive, negative) {
+            Ok(())
+        } else {
+            Err(Error::AmountMismatch)
+        }
+    }
+
+    pub fn kernel_features(&self) -> KernelFeatures {
+        KernelFeatures::for_lock_height(self.lock_height)
+    }
+
+    // Message signed by both parties.
+    pub fn kernel_message(&self) -> Vec<u8> {
+        kernel_message(self.kernel_features(), self.fee, self.lock_height)
+    }
+
+    // Kernel excess given the public blinding of the receiver.
+    pub fn excess(&self, secp: &Secp256k1, blinding: &Commitment) -> Result<Commitment> {
+        Ok(secp.commit_sum(vec![self.sum_of_bliding_factors, *blinding], vec![])?)
+    }
+
+    // Aggregate the receiver's and the sender's partial signatures into
+    // the final transaction. Both signatures have to be checked already.
+    pub fn finalize(
+        &self,
+        secp: &Secp256k1,
+        resp: &Response,
+        sign: &SecretKey
+    ) -> Result<Transaction> {
+        // Sum partial signatures.
+        let partials_sum = secp.blind_sum(vec![resp.sign, *sign], vec![])?;
+
+        // Sum nonces.
+        let nonces_sum = secp.commit_sum(vec![resp.nonce, self.nonce], vec![])?;
+
+        let kernel = TxKernel {
+            features: self.kernel_features(),
+            fee: self.fee,
+            lock_height: self.lock_height,
+            excess: self.excess(secp, &resp.blinding)?,
+            excess_sig: TxSignature { partials_sum, nonces_sum }
+        };
+
+        Ok(Transaction {
+            inputs: vec![self.input],
+            outputs: vec![self.change_output.clone(), resp.output.clone()],
+            kernels: vec![kernel],
+            kernel_offset: self.kernel_offset
+        })
+    }
+
+    // Challenge for the public nonce and blinding of the receiver.
+    pub fn challenge(
+        &self,
+        secp: &Secp256k1,
+        nonce: &Commitment,
+        blinding: &Commitment
+    ) -> Result<SecretKey> {
+        let nonces_sum = secp.commit_sum(vec![self.nonce, *nonce], vec![])?;
+        let excess = self.excess(secp, blinding)?;
+        challenge(secp, &nonces_sum, &excess, &self.kernel_message())
+    }
+}
+
+impl Writeable for Message {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        writer.write_u64(self.amount);
+        writer.write_u64(self.fee);
+        writer.write_u64(self.lock_height);
+        self.input.write(writer)?;
+        self.change_output.write(writer)?;
+        self.nonce.write(writer)?;
+        self.sum_of_bliding_factors.write(writer)?;
+        self.kernel_offset.write(writer)
+    }
+}
+
+impl Readable for Message {
+    fn read(reader: &mut Reader) -> Result<Message> {
+        Ok(Message {
+            amount: reader.read_u64()?,
+            fee: reader.read_u64()?,
+            lock_height: reader.read_u64()?,
+            input: Commitment::read(reader)?,
+            change_output: Output::read(reader)?,
+            nonce: Commitment::read(reader)?,
+            sum_of_bliding_factors: Commitment::read(reader)?,
+            kernel_offset: reader.read_kernel_offset()?
+        })
+    }
+}
+
+// Sent back by the receiver with its output and partial signature.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Response {
+    #[cfg_attr(feature = "serde", serde(with = "crate::slate::secret_key_hex"))]
+    pub sign: SecretKey,
+    #[cfg_attr(feature = "serde", serde(with = "crate::slate::commitment_hex"))]
+    pub nonce: Commitment,
+    #[cfg_attr(feature = "serde", serde(with = "crate::slate::commitment_hex"))]
+    pub blinding: Commitment,
+    pub output: Output
+}
+
+impl Response {
+    // Check that the receiver's output commits to `msg.amount` with the
+    // blinding factor behind its public blinding:
+    // output - amount * H == blinding
+    // and that it has a valid range proof.
+    pub fn verify(&self, secp: &Secp256k1, msg: &Message) -> Result<()> {
+        verify_output(secp, &self.output, msg.amount, &self.blinding)
+    }
+}
+
+// output - amount * H == blinding, and a valid range proof.
+pub(crate) fn verify_output(
+    secp: &Secp256k1,
+    output: &Output,
+    amount: u64,
+    blinding: &Commitment
+) -> Result<()> {
+    output.verify(secp)?;
+    let amount = secp.commit_value(amount)?;
+    if secp.verify_commit_sum(vec![output.commit], vec![amount, *blinding]) {
+        Ok(())
+    } else {
+        Err(Error::OutputMismatch)
+    }
+}
+
+impl Writeable for Response {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        self.sign.write(writer)?;
+        self.nonce.write(writer)?;
+        self.blinding.write(writer)?;
+        self.output.write(writer)
+    }
+}
+
+impl Readable for Response {
+    fn read(reader: &mut Reader) -> Result<Response> {
+        Ok(Response {
+            sign: SecretKey::read(reader)?,
+            nonce: Commitment::read(reader)?,
+            blinding: Commitment::read(reader)?,
+            output: Output::read(reader)?
+        })
+    }
+}
+
+// Secrets chosen by the sender. Only tests pick them: signing twice with the
+// same nonce and different challenges leaks the blinding sum.
+#[derive(Debug, Clone)]
+pub(crate) struct SenderKeys {
+    pub(crate) change_blinding: SecretKey,
+    pub(crate) nonce: SecretKey,
+    pub(crate) kernel_offset: SecretKey
+}
+
+impl SenderKeys {
+    pub(crate) fn random(secp: &Secp256k1) -> SenderKeys {
+        SenderKeys {
+            change_blinding: rand_blinding(secp),
+            nonce: rand_blinding(secp),
+            kernel_offset: rand_blinding(secp)
+        }
+    }
+}
+
+// Sender's side of the transfer. Keeps the secrets needed to finalize.
+pub struct Sender {
+    pub message: Message,
+    change_blinding: SecretKey,
+    blinding_sum: SecretKey,
+    nonce: SecretKey
+}
+
+impl Sender {
+    // Lowest fee relayed for a transfer: one input, change and the
+    // receiver's output, one kernel.
+    pub fn min_fee(params: &ChainParams) -> u64 {
+        params.min_fee(consensus::weight(1, 2, 1))
+    }
+
+    // Spend the input worth `input_value` and send `amount` paying `fee`.
+    // The rest goes back to the sender as change.
+    pub fn initiate(
+        secp: &Secp256k1,
+        input_value: u64,
+        input_blinding: &SecretKey,
+        amount: u64,
+        fee: u64
+    ) -> Result<Sender> {
+        let keys = SenderKeys::random(secp);
+        Sender::initiate_with_keys(secp, input_value, input_blinding, amount, fee, keys)
+    }
+
+    pub(crate) fn initiate_with_keys(
+        secp: &Secp256k1,
+        input_value: u64,
+        input_blinding: &SecretKey,
+        amount: u64,
+        fee: u64,
+        keys: SenderKeys
+    ) -> Result<Sender> {
+        let change_value = amount.checked_add(fee)
+            .and_then(|spent| input_value.checked_sub(spent))
+            .ok_or(Error::AmountMismatch)?;
+
+        let input = commit(secp, input_value, input_blinding)?;
+
+        // Change output.
+        let change_blinding = keys.change_blinding;
+        let change_output = Output::new(secp, change_value, &change_blinding)?;
+
+        // Nonce.
+        let nonce = keys.nonce;
+
+        // Kernel offset. It's split off the sum of blinding factors, so the
+        // kernel excess alone doesn't match inputs and outputs once the
+        // transaction is aggregated with others.
+        let kernel_offset = keys.kernel_offset;
+
+        // Sum of all blinding factors, minus the offset.
+        let blinding_sum = secp.blind_sum(
+            vec![change_blinding],
+            vec![*input_blinding, kernel_offset]
+        )?;
+
+        let message = Message {
+            amount,
+            fee,
+            lock_height: 0,
+            input,
+            change_output,
+            nonce: commit(secp, 0, &nonce)?,
+            sum_of_bliding_factors: commit(secp, 0, &blinding_sum)?,
+            kernel_offset
+        };
+
+        Ok(Sender { message, change_blinding, blinding_sum, nonce })
+    }
+
+    // Lock the transaction until `lock_height`, before sending the message.
+    pub fn with_lock_height(mut self, lock_height: u64) -> Sender {
+        self.message.lock_height = lock_height;
+        self
+    }
+
+    pub fn change_blinding(&self) -> &SecretKey {
+        &self.change_blinding
+    }
+
+    // Sender's partial signature given the receiver's public nonce and
+    // blinding. Only signed once per sender, by finalize or by the payer.
+    pub(crate) fn sign(
+        &self,
+        secp: &Secp256k1,
+        nonce: &Commitment,
+        blinding: &Commitment
+    ) -> Result<SecretKey> {
+        let e = self.message.challenge(secp, nonce, blinding)?;
+        sign_partial(secp, &self.blinding_sum, &self.nonce, &e)
+    }
+
+    // Check the receiver's output and partial signature, add ours and
+    // build the final transaction. Consumes the sender, as signing a second
+    // response with the same nonce would leak the blinding sum.
+    pub fn finalize(self, secp: &Secp256k1, resp: &Response) -> Result<Transaction> {
+        let msg = &self.message;
+        resp.verify(secp, msg)?;
+        let e = msg.challenge(secp, &resp.nonce, &resp.blinding)?;
+        verify_partial(secp, &resp.sign, &resp.nonce, &resp.blinding, &e)?;
+
+        // Sender's signature.
+        let sign = self.sign(secp, &resp.nonce, &resp.blinding)?;
+
+        msg.finalize(secp, resp, &sign)
+    }
+}
+
+// Secrets chosen by the receiver, see `SenderKeys`.
+#[derive(Debug, Clone)]
+pub(crate) struct ReceiverKeys {
+    pub(crate) output_blinding: SecretKey,
+    pub(crate) nonce: SecretKey
+}
+
+impl ReceiverKeys {
+    pub(crate) fn random(secp: &Secp256k1) -> ReceiverKeys {
+        ReceiverKeys {
+            output_blinding: rand_blinding(secp),
+            nonce: rand_blinding(secp)
+        }
+    }
+}
+
+// Receiver's side of the transfer.
+pub struct Receiver {
+    pub response: Response,
+    output_blinding: SecretKey
+}
+
+impl Receiver {
+    // Check the sender's message, create an output for `msg.amount` and
+    // sign for it.
+    pub fn respond(secp: &Secp256k1, msg: &Message) -> Result<Receiver> {
+        Receiver::respond_with_keys(secp, msg, ReceiverKeys::random(secp))
+    }
+
+    pub(crate) fn respond_with_keys(
+        secp: &Secp256k1,
+        msg: &Message,
+        keys: ReceiverKeys
+    ) -> Result<Receiver> {
+        msg.verify(secp)?;
+
+        // Nonce.
+        let nonce = keys.nonce;
+        let nonce_commit = commit(secp, 0, &nonce)?;
+
+        // Blinding of the new output.
+        let output_blinding = keys.output_blinding;
+        let blinding_commit = commit(secp, 0, &output_blinding)?;
+
+        // Partial signature.
+        let e = msg.challenge(secp, &nonce_commit, &blinding_commit)?;
+        let sign = sign_partial(secp, &output_blinding, &nonce, &e)?;
+
+        let response = Response {
+            sign,
+            nonce: nonce_commit,
+            blinding: blinding_commit,
+            output: Output::new(secp, msg.amount, &output_blinding)?
+        };
+
+        Ok(Receiver { response, output_blinding })
+    }
+
+    pub fn output_blinding(&self) -> &SecretKey {
+        &self.output_blinding
+    }
+}
+
+#[test]
+fn test_transfer() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    // Alice input.
+    let ali_input_blinding = rand_blinding(&secp);
+
+    // Alice sends 25 out of 40, paying no fee.
+    let ali = Sender::initiate(&secp, 40, &ali_input_blinding, 25, 0).unwrap();
+    assert_eq!(ali.message.input, commit(&secp, 40, &ali_input_blinding).unwrap());
+    assert_eq!(
+        ali.message.change_output.commit,
+        commit(&secp, 15, ali.change_blinding()).unwrap()
+    );
+
+    // Bob's part.
+    let bob = Receiver::respond(&secp, &ali.message).unwrap();
+    assert_eq!(
+        bob.response.output.commit,
+        commit(&secp, 25, bob.output_blinding()).unwrap()
+    );
+
+    // Back to Alice, keeping her change blinding for the check below.
+    let ali_change_blinding = *ali.change_blinding();
+    let tx = ali.finalize(&secp, &bob.response).unwrap();
+
+    // Kernel
+    assert_eq!(tx.kernels[0].excess, tx.kernel_excess(&secp).unwrap());
+
+    // Check
+    {
+        let sum_blinding = secp.blind_sum(
+            vec![ali_change_blinding, *bob.output_blinding()],
+            vec![ali_input_blinding, tx.kernel_offset]
+        ).unwrap();
+        assert_eq!(tx.kernels[0].excess, commit(&secp, 0, &sum_blinding).unwrap());
+    }
+
+    // Without the offset outputs - inputs doesn't reveal the kernel.
+    {
+        let outputs = tx.outputs.iter().map(|o| o.commit).collect();
+        let naive = secp.commit_sum(outputs, tx.inputs.clone()).unwrap();
+        assert_ne!(tx.kernels[0].excess, naive);
+    }
+
+    // Validate tx
+    assert_eq!(tx.validate(&secp), Ok(()));
+}
+
+#[test]
+fn test_transfer_with_fee() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let ali_input_blinding = rand_blinding(&secp);
+    let ali = Sender::initiate(&secp, 40, &ali_input_blinding, 25, 3).unwrap();
+    assert_eq!(
+        ali.message.change_output.commit,
+        commit(&secp, 12, ali.change_blinding()).unwrap()
+    );
+
+    let bob = Receiver::respond(&secp, &ali.message).unwrap();
+    let tx = ali.finalize(&secp, &bob.response).unwrap();
+    assert_eq!(tx.kernels[0].fee, 3);
+    assert_eq!(tx.validate(&secp), Ok(()));
+
+    // Paying exactly the lowest relayed fee.
+    let params = ChainParams { min_fee_rate: 2, ..ChainParams::default() };
+    let fee = Sender::min_fee(&params);
+    let ali = Sender::initiate(&secp, 1_000, &ali_input_blinding, 25, fee).unwrap();
+    let bob = Receiver::respond(&secp, &ali.message).unwrap();
+    let tx = ali.finalize(&secp, &bob.response).unwrap();
+    assert_eq!(tx.fee(), Ok(params.min_fee(tx.weight())));
+}
+
+#[test]
+fn test_transfer_height_locked() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let ali = Sender::initiate(&secp, 40, &rand_blinding(&secp), 25, 3)
+        .unwrap()
+        .with_lock_height(100);
+    let bob = Receiver::respond(&secp, &ali.message).unwrap();
+    let tx = ali.finalize(&secp, &bob.response).unwrap();
+    assert_eq!(tx.kernels[0].features, KernelFeatures::HeightLocked);
+    assert_eq!(tx.lock_height(), 100);
+    assert_eq!(tx.validate(&secp), Ok(()));
+
+    // The lock height is signed.
+    let mut other = tx.clone();
+    other.kernels[0].lock_height = 1;
+    assert_eq!(other.validate(&secp), Err(Error::IncorrectSignature));
+}
+
+#[test]
+fn test_transfer_insufficient_input() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let blinding = rand_blinding(&secp);
+    assert!(Sender::initiate(&secp, 40, &blinding, 41, 0).is_err());
+    assert!(Sender::initiate(&secp, 40, &blinding, 38, 3).is_err());
+    assert!(Sender::initiate(&secp, 40, &blinding, u64::MAX, 1).is_err());
+}
+
+#[test]
+fn test_transfer_bad_partial_signature() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let ali = Sender::initiate(&secp, 40, &rand_blinding(&secp), 25, 0).unwrap();
+    let mut bob = Receiver::respond(&secp, &ali.message).unwrap();
+    bob.response.sign = rand_blinding(&secp);
+
+    assert_eq!(
+        ali.finalize(&secp, &bob.response).err(),
+        Some(Error::BadPartialSignature)
+    );
+}
+
+#[test]
+fn test_message_verify() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let ali = Sender::initiate(&secp, 40, &rand_blinding(&secp), 25, 2).unwrap();
+    assert_eq!(ali.message.verify(&secp), Ok(()));
+
+    // Amount not matching the change.
+    let mut msg = ali.message.clone();
+    msg.amount = 26;
+    assert_eq!(msg.verify(&secp), Err(Error::AmountMismatch));
+    assert_eq!(Receiver::respond(&secp, &msg).err(), Some(Error::AmountMismatch));
+
+    let mut msg = ali.message.clone();
+    msg.fee = 1;
+    assert_eq!(msg.verify(&secp), Err(Error::AmountMismatch));
+
+    let mut msg = ali.message.clone();
+    msg.amount = 0;
+    assert_eq!(msg.verify(&secp), Err(Error::AmountMismatch));
+
+    let mut msg = ali.message.clone();
+    msg.fee = u64::MAX;
+    assert_eq!(msg.verify(&secp), Err(Error::AmountMismatch));
+
+    // Public blinding sum not matching input and change.
+    let mut msg = ali.message.clone();
+    msg.sum_of_bliding_factors = commit(&secp, 0, &rand_blinding(&secp)).unwrap();
+    assert_eq!(msg.verify(&secp), Err(Error::AmountMismatch));
+
+    let mut msg = ali.message.clone();
+    msg.kernel_offset = rand_blinding(&secp);
+    assert_eq!(msg.verify(&secp), Err(Error::AmountMismatch));
+
+    // Change output with someone else's range proof.
+    let other = Sender::initiate(&secp, 40, &rand_blinding(&secp), 25, 2).unwrap();
+    let mut msg = ali.message.clone();
+    msg.change_output.proof = other.message.change_output.proof;
+    assert_eq!(msg.verify(&secp), Err(Error::InvalidRangeProof));
+    assert_eq!(Receiver::respond(&secp, &msg).err(), Some(Error::InvalidRangeProof));
+}
+
+#[test]
+fn test_response_verify() {
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    // Finalizing consumes the sender, so each bad response gets a new transfer.
+    let transfer = || {
+        let ali = Sender::initiate(&secp, 40, &rand_blinding(&secp), 25, 2).unwrap();
+        let bob = Receiver::respond(&secp, &ali.message).unwrap();
+        (ali, bob)
+    };
+
+    let (ali, bob) = transfer();
+    assert_eq!(bob.response.verify(&secp, &ali.message), Ok(()));
+
+    // Output for a different amount, with a valid range proof.
+    let mut resp = bob.response.clone();
+    resp.output = Output::new(&secp, 5, bob.output_blinding()).unwrap();
+    assert_eq!(resp.verify(&secp, &ali.message), Err(Error::OutputMismatch));
+    assert_eq!(ali.finalize(&secp, &resp).err(), Some(Error::OutputMismatch));
+
+    // Output with a blinding factor other than the signed one.
+    let (ali, bob) = transfer();
+    let mut resp = bob.response.clone();
+    resp.output = Output::new(&secp, 25, &rand_blinding(&secp)).unwrap();
+    assert_eq!(ali.finalize(&secp, &resp).err(), Some(Error::OutputMismatch));
+
+    // Output with someone else's range proof.
+    let (ali, bob) = transfer();
+    let mut resp = bob.response.clone();
+    resp.output.proof = ali.message.change_output.proof;
+    assert_eq!(ali.finalize(&secp, &resp).err(), Some(Error::InvalidRangeProof));
+}
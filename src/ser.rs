@@ -0,0 +1,370 @@
+use std::fmt;
+
+use secp256k1::{
+    Secp256k1, ContextFlag,
+    constants::{MAX_PROOF_SIZE, PEDERSEN_COMMITMENT_SIZE, SECRET_KEY_SIZE},
+    key::{SecretKey, ZERO_KEY},
+    pedersen::{Commitment, RangeProof},
+};
+use sha2::{Sha256, Digest};
+use rand::thread_rng;
+
+use crate::{Hash, Result};
+
+// Version byte prefixing every serialized object, bumped on every change
+// of the encoding. Only the current version is read.
+// 2: kernel offset in `Message`.
+// 3: features byte in `Output`.
+// 4: lock height in `Message`.
+pub const PROTOCOL_VERSION: u8 = 4;
+
+// Maximum number of items in a serialized vector.
+pub const MAX_VEC_LEN: u32 = 10_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    // Data was serialized with a version we don't know.
+    UnsupportedVersion(u8),
+    // Data ends before the object is complete.
+    UnexpectedEof,
+    // Data continues after the object is complete.
+    TrailingBytes(usize),
+    // Vector or range proof longer than allowed.
+    TooLarge(u64),
+    // Bytes don't decode to a valid value.
+    CorruptedData
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            Error::UnexpectedEof => write!(f, "unexpected end of data"),
+            Error::TrailingBytes(n) => write!(f, "{} trailing bytes", n),
+            Error::TooLarge(n) => write!(f, "length {} too large", n),
+            Error::CorruptedData => write!(f, "corrupted data")
+        }
+    }
+}
+
+pub trait Writeable {
+    fn write(&self, writer: &mut Writer) -> Result<()>;
+}
+
+pub trait Readable: Sized {
+    fn read(reader: &mut Reader) -> Result<Self>;
+}
+
+pub struct Writer {
+    buf: Vec<u8>
+}
+
+impl Writer {
+    pub fn write_u8(&mut self, n: u8) {
+        self.buf.push(n);
+    }
+
+    pub fn write_u16(&mut self, n: u16) {
+        self.buf.extend_from_slice(&n.to_be_bytes());
+    }
+
+    pub fn write_u32(&mut self, n: u32) {
+        self.buf.extend_from_slice(&n.to_be_bytes());
+    }
+
+    pub fn write_u64(&mut self, n: u64) {
+        self.buf.extend_from_slice(&n.to_be_bytes());
+    }
+
+    pub fn write_fixed_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    // Length-prefixed vector of at most MAX_VEC_LEN items.
+    pub fn write_vec<T: Writeable>(&mut self, items: &[T]) -> Result<()> {
+        if items.len() > MAX_VEC_LEN as usize {
+            return Err(Error::TooLarge(items.len() as u64).into());
+        }
+        self.write_u32(items.len() as u32);
+        for item in items {
+            item.write(self)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Reader<'a> {
+    secp: &'a Secp256k1,
+    data: &'a [u8]
+}
+
+impl<'a> Reader<'a> {
+    pub fn secp(&self) -> &'a Secp256k1 {
+        self.secp
+    }
+
+    pub fn read_fixed_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(Error::UnexpectedEof.into());
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_fixed_bytes(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.read_fixed_bytes(2)?);
+        Ok(u16::from_be_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.read_fixed_bytes(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.read_fixed_bytes(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    // Kernel offsets are the only secret keys that can be zero, when empty.
+    pub fn read_kernel_offset(&mut self) -> Result<SecretKey> {
+        if self.data.starts_with(&ZERO_KEY.0[..]) {
+            self.data = &self.data[SECRET_KEY_SIZE..];
+            return Ok(ZERO_KEY);
+        }
+        SecretKey::read(self)
+    }
+
+    pub fn read_vec<T: Readable>(&mut self) -> Result<Vec<T>> {
+        let len = self.read_u32()?;
+        if len > MAX_VEC_LEN {
+            return Err(Error::TooLarge(u64::from(len)).into());
+        }
+        let mut items = vec![];
+        for _ in 0..len {
+            items.push(T::read(self)?);
+        }
+        Ok(items)
+    }
+}
+
+// Serialize with the version prefix.
+pub fn serialize<T: Writeable>(thing: &T) -> Result<Vec<u8>> {
+    let mut writer = Writer { buf: vec![PROTOCOL_VERSION] };
+    thing.write(&mut writer)?;
+    Ok(writer.buf)
+}
+
+// Deserialize a whole buffer, rejecting unknown versions and trailing bytes.
+pub fn deserialize<T: Readable>(secp: &Secp256k1, data: &[u8]) -> Result<T> {
+    let mut reader = Reader { secp, data };
+    let version = reader.read_u8()?;
+    if version != PROTOCOL_VERSION {
+        return Err(Error::UnsupportedVersion(version).into());
+    }
+    let thing = T::read(&mut reader)?;
+    if !reader.data.is_empty() {
+        return Err(Error::TrailingBytes(reader.data.len()).into());
+    }
+    Ok(thing)
+}
+
+// SHA-256 of the serialized object.
+pub fn hash<T: Writeable>(thing: &T) -> Result<Hash> {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&Sha256::digest(&serialize(thing)?));
+    Ok(hash)
+}
+
+impl Writeable for Commitment {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        writer.write_fixed_bytes(&self.0[..]);
+        Ok(())
+    }
+}
+
+impl Readable for Commitment {
+    fn read(reader: &mut Reader) -> Result<Commitment> {
+        let bytes = reader.read_fixed_bytes(PEDERSEN_COMMITMENT_SIZE)?;
+        Ok(Commitment::from_vec(bytes.to_vec()))
+    }
+}
+
+impl Writeable for SecretKey {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        writer.write_fixed_bytes(&self.0[..]);
+        Ok(())
+    }
+}
+
+impl Readable for SecretKey {
+    fn read(reader: &mut Reader) -> Result<SecretKey> {
+        let bytes = reader.read_fixed_bytes(SECRET_KEY_SIZE)?;
+        SecretKey::from_slice(reader.secp(), bytes)
+            .map_err(|_| Error::CorruptedData.into())
+    }
+}
+
+impl Writeable for Hash {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        writer.write_fixed_bytes(&self[..]);
+        Ok(())
+    }
+}
+
+impl Readable for Hash {
+    fn read(reader: &mut Reader) -> Result<Hash> {
+        let mut hash = [0; 32];
+        hash.copy_from_slice(reader.read_fixed_bytes(32)?);
+        Ok(hash)
+    }
+}
+
+impl Writeable for RangeProof {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        writer.write_u16(self.plen as u16);
+        writer.write_fixed_bytes(&self.proof[..self.plen]);
+        Ok(())
+    }
+}
+
+impl Readable for RangeProof {
+    fn read(reader: &mut Reader) -> Result<RangeProof> {
+        let plen = reader.read_u16()? as usize;
+        if plen > MAX_PROOF_SIZE {
+            return Err(Error::TooLarge(plen as u64).into());
+        }
+        let mut proof = [0; MAX_PROOF_SIZE];
+        proof[..plen].copy_from_slice(reader.read_fixed_bytes(plen)?);
+        Ok(RangeProof { proof, plen })
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    use crate::{
+        rand_blinding, Invoice, Message, Payee, Payer, Payment, Response, Sender, Receiver,
+        Transaction, TxKernel,
+    };
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let ali = Sender::initiate(&secp, 40, &rand_blinding(&secp), 25, 2).unwrap();
+    let bob = Receiver::respond(&secp, &ali.message).unwrap();
+    let message = ali.message.clone();
+    let tx = ali.finalize(&secp, &bob.response).unwrap();
+
+    let data = serialize(&message).unwrap();
+    assert_eq!(deserialize::<Message>(&secp, &data).unwrap(), message);
+
+    let data = serialize(&bob.response).unwrap();
+    assert_eq!(deserialize::<Response>(&secp, &data).unwrap(), bob.response);
+
+    let data = serialize(&tx.kernels[0]).unwrap();
+    assert_eq!(deserialize::<TxKernel>(&secp, &data).unwrap(), tx.kernels[0]);
+
+    let data = serialize(&tx).unwrap();
+    let tx2 = deserialize::<Transaction>(&secp, &data).unwrap();
+    assert_eq!(tx2, tx);
+    assert_eq!(tx2.validate(&secp), Ok(()));
+
+    // Invoice flow.
+    let bob = Payee::invoice(&secp, 25).unwrap();
+    let data = serialize(&bob.invoice).unwrap();
+    let invoice = deserialize::<Invoice>(&secp, &data).unwrap();
+    assert_eq!(invoice, bob.invoice);
+
+    let ali = Payer::pay(&secp, &invoice, 40, &rand_blinding(&secp), 2).unwrap();
+    let data = serialize(&ali.payment).unwrap();
+    let payment = deserialize::<Payment>(&secp, &data).unwrap();
+    assert_eq!(payment, ali.payment);
+    assert_eq!(bob.finalize(&secp, &payment).unwrap().validate(&secp), Ok(()));
+}
+
+#[test]
+fn test_strict_deserialize() {
+    use crate::{rand_blinding, Sender, Receiver, Response, Transaction, Error as TxError};
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let ali = Sender::initiate(&secp, 40, &rand_blinding(&secp), 25, 2).unwrap();
+    let bob = Receiver::respond(&secp, &ali.message).unwrap();
+    let tx = ali.finalize(&secp, &bob.response).unwrap();
+    let data = serialize(&tx).unwrap();
+
+    // Trailing bytes.
+    let mut longer = data.clone();
+    longer.push(0);
+    assert_eq!(
+        deserialize::<Transaction>(&secp, &longer),
+        Err(TxError::Ser(Error::TrailingBytes(1)))
+    );
+
+    // Truncated.
+    assert_eq!(
+        deserialize::<Transaction>(&secp, &data[..data.len() - 1]),
+        Err(TxError::Ser(Error::UnexpectedEof))
+    );
+
+    // Unknown version.
+    let mut other_version = data.clone();
+    other_version[0] = PROTOCOL_VERSION + 1;
+    assert_eq!(
+        deserialize::<Transaction>(&secp, &other_version),
+        Err(TxError::Ser(Error::UnsupportedVersion(PROTOCOL_VERSION + 1)))
+    );
+
+    // Zero keys are only read as kernel offsets.
+    let mut unsigned = bob.response.clone();
+    unsigned.sign = ZERO_KEY;
+    assert_eq!(
+        deserialize::<Response>(&secp, &serialize(&unsigned).unwrap()),
+        Err(TxError::Ser(Error::CorruptedData))
+    );
+    let mut no_offset = tx.clone();
+    no_offset.kernel_offset = ZERO_KEY;
+    let read = deserialize::<Transaction>(&secp, &serialize(&no_offset).unwrap()).unwrap();
+    assert_eq!(read, no_offset);
+
+    // Older version.
+    let mut old_version = data.clone();
+    old_version[0] = PROTOCOL_VERSION - 1;
+    assert_eq!(
+        deserialize::<Transaction>(&secp, &old_version),
+        Err(TxError::Ser(Error::UnsupportedVersion(PROTOCOL_VERSION - 1)))
+    );
+
+    // Oversized inputs vector.
+    let mut oversized = data.clone();
+    oversized[1..5].copy_from_slice(&(MAX_VEC_LEN + 1).to_be_bytes());
+    assert_eq!(
+        deserialize::<Transaction>(&secp, &oversized),
+        Err(TxError::Ser(Error::TooLarge(u64::from(MAX_VEC_LEN) + 1)))
+    );
+
+    // Oversized range proof.
+    let mut writer = Writer { buf: vec![PROTOCOL_VERSION] };
+    writer.write_u16(MAX_PROOF_SIZE as u16 + 1);
+    assert_eq!(
+        deserialize::<RangeProof>(&secp, &writer.buf),
+        Err(TxError::Ser(Error::TooLarge(MAX_PROOF_SIZE as u64 + 1)))
+    );
+
+    // Vectors longer than the limit can't be written either.
+    let inputs = vec![tx.inputs[0]; MAX_VEC_LEN as usize + 1];
+    let mut writer = Writer { buf: vec![] };
+    assert_eq!(
+        writer.write_vec(&inputs),
+        Err(TxError::Ser(Error::TooLarge(u64::from(MAX_VEC_LEN) + 1)))
+    );
+}
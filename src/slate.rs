@@ -0,0 +1,383 @@
+use serde::{Serialize, Deserialize};
+use secp256k1::{
+    Secp256k1, ContextFlag,
+    key::{SecretKey, ZERO_KEY},
+    pedersen::Commitment,
+};
+use rand::thread_rng;
+
+use crate::Result;
+use crate::protocol::{Message, Response};
+use crate::ser;
+use crate::transaction::Output;
+
+// In-progress transfer passed between the parties as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Slate {
+    pub message: Message,
+    pub response: Option<Response>
+}
+
+// Slate tagged with the version of its format. Reading a slate goes through
+// this envelope, so slates written by older versions are recognized and
+// either upgraded to the current `Slate` or rejected as unsupported.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "version")]
+pub enum VersionedSlate {
+    #[serde(rename = "1")]
+    V1(SlateV1),
+    #[serde(rename = "2")]
+    V2(SlateV2),
+    #[serde(rename = "3")]
+    V3(Slate)
+}
+
+impl From<Slate> for VersionedSlate {
+    fn from(slate: Slate) -> VersionedSlate {
+        VersionedSlate::V3(slate)
+    }
+}
+
+impl VersionedSlate {
+    pub fn into_slate(self) -> Result<Slate> {
+        match self {
+            // The sender's kernel offset was folded into the sum of blinding
+            // factors, and can't be split off again without its secrets.
+            VersionedSlate::V1(_) => Err(ser::Error::UnsupportedVersion(1).into()),
+            VersionedSlate::V2(slate) => Ok(slate.into()),
+            VersionedSlate::V3(slate) => Ok(slate)
+        }
+    }
+}
+
+// Version 1: the message didn't carry the kernel offset.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SlateV1 {
+    pub message: MessageV1,
+    pub response: Option<Response>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageV1 {
+    pub amount: u64,
+    pub fee: u64,
+    #[serde(with = "commitment_hex")]
+    pub input: Commitment,
+    pub change_output: Output,
+    #[serde(with = "commitment_hex")]
+    pub nonce: Commitment,
+    #[serde(with = "commitment_hex")]
+    pub sum_of_bliding_factors: Commitment
+}
+
+// Version 2: the message didn't carry the lock height.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SlateV2 {
+    pub message: MessageV2,
+    pub response: Option<Response>
+}
+
+// Unknown fields are rejected, so a locked message isn't read as unlocked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MessageV2 {
+    pub amount: u64,
+    pub fee: u64,
+    #[serde(with = "commitment_hex")]
+    pub input: Commitment,
+    pub change_output: Output,
+    #[serde(with = "commitment_hex")]
+    pub nonce: Commitment,
+    #[serde(with = "commitment_hex")]
+    pub sum_of_bliding_factors: Commitment,
+    #[serde(with = "kernel_offset_hex")]
+    pub kernel_offset: SecretKey
+}
+
+// Messages without a lock height signed plain kernels.
+impl From<SlateV2> for Slate {
+    fn from(slate: SlateV2) -> Slate {
+        let msg = slate.message;
+        let message = Message {
+            amount: msg.amount,
+            fee: msg.fee,
+            lock_height: 0,
+            input: msg.input,
+            change_output: msg.change_output,
+            nonce: msg.nonce,
+            sum_of_bliding_factors: msg.sum_of_bliding_factors,
+            kernel_offset: msg.kernel_offset
+        };
+        Slate { message, response: slate.response }
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix alone would take a sign, like "+a".
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) || hex.len() % 2 == 1 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn secret_key_from_hex(hex: &str, allow_zero: bool) -> Option<SecretKey> {
+    let bytes = from_hex(hex)?;
+    if allow_zero && bytes[..] == ZERO_KEY.0[..] {
+        return Some(ZERO_KEY);
+    }
+    let secp = Secp256k1::with_caps(ContextFlag::None);
+    SecretKey::from_slice(&secp, &bytes).ok()
+}
+
+// Serde helpers encoding secp256k1 types as hex strings. Used through
+// `#[serde(with = "...")]` on the fields of the exchanged types.
+
+pub mod commitment_hex {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+    use secp256k1::{constants::PEDERSEN_COMMITMENT_SIZE, pedersen::Commitment};
+
+    pub fn serialize<S: Serializer>(commit: &Commitment, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&super::to_hex(&commit.0[..]))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Commitment, D::Error> {
+        let hex = String::deserialize(d)?;
+        match super::from_hex(&hex) {
+            Some(ref bytes) if bytes.len() == PEDERSEN_COMMITMENT_SIZE => {
+                Ok(Commitment::from_vec(bytes.clone()))
+            },
+            _ => Err(D::Error::custom("invalid commitment"))
+        }
+    }
+}
+
+pub mod secret_key_hex {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+    use secp256k1::key::SecretKey;
+
+    pub fn serialize<S: Serializer>(key: &SecretKey, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&super::to_hex(&key.0[..]))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SecretKey, D::Error> {
+        let hex = String::deserialize(d)?;
+        super::secret_key_from_hex(&hex, false)
+            .ok_or_else(|| D::Error::custom("invalid secret key"))
+    }
+}
+
+// Kernel offsets are the only secret keys that can be zero, when empty.
+pub mod kernel_offset_hex {
+    use serde::{Deserialize, Deserializer, de::Error};
+    use secp256k1::key::SecretKey;
+
+    pub use super::secret_key_hex::serialize;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SecretKey, D::Error> {
+        let hex = String::deserialize(d)?;
+        super::secret_key_from_hex(&hex, true)
+            .ok_or_else(|| D::Error::custom("invalid kernel offset"))
+    }
+}
+
+pub mod range_proof_hex {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+    use secp256k1::{constants::MAX_PROOF_SIZE, pedersen::RangeProof};
+
+    pub fn serialize<S: Serializer>(proof: &RangeProof, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&super::to_hex(&proof.proof[..proof.plen]))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<RangeProof, D::Error> {
+        let hex = String::deserialize(d)?;
+        match super::from_hex(&hex) {
+            Some(ref bytes) if bytes.len() <= MAX_PROOF_SIZE => {
+                let mut proof = [0; MAX_PROOF_SIZE];
+                proof[..bytes.len()].copy_from_slice(bytes);
+                Ok(RangeProof { proof, plen: bytes.len() })
+            },
+            _ => Err(D::Error::custom("invalid range proof"))
+        }
+    }
+}
+
+#[test]
+fn test_slate_json() {
+    use crate::{rand_blinding, Sender, Receiver};
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let ali = Sender::initiate(&secp, 40, &rand_blinding(&secp), 25, 2).unwrap();
+
+    // Alice -> Bob.
+    let slate = Slate { message: ali.message.clone(), response: None };
+    let json = serde_json::to_string(&VersionedSlate::from(slate.clone())).unwrap();
+    assert!(json.contains("\"version\":\"3\""));
+    assert!(json.contains(&to_hex(&ali.message.input.0[..])));
+    let read: VersionedSlate = serde_json::from_str(&json).unwrap();
+    assert_eq!(read.into_slate(), Ok(slate.clone()));
+
+    // Bob -> Alice.
+    let bob = Receiver::respond(&secp, &slate.message).unwrap();
+    let slate = Slate { response: Some(bob.response.clone()), ..slate };
+    let json = serde_json::to_string(&VersionedSlate::from(slate.clone())).unwrap();
+    assert!(json.contains(&to_hex(&bob.response.sign.0[..])));
+    let read: VersionedSlate = serde_json::from_str(&json).unwrap();
+    let read = read.into_slate().unwrap();
+    assert_eq!(read, slate);
+
+    let tx = ali.finalize(&secp, read.response.as_ref().unwrap()).unwrap();
+    assert_eq!(tx.validate(&secp), Ok(()));
+}
+
+#[test]
+fn test_slate_json_rejects() {
+    use crate::{rand_blinding, Sender, Receiver};
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let ali = Sender::initiate(&secp, 40, &rand_blinding(&secp), 25, 2).unwrap();
+    let slate = Slate { message: ali.message.clone(), response: None };
+    let json = serde_json::to_string(&VersionedSlate::from(slate)).unwrap();
+
+    // Unknown version.
+    let other = json.replace("\"version\":\"3\"", "\"version\":\"99\"");
+    assert!(serde_json::from_str::<VersionedSlate>(&other).is_err());
+
+    // Version 3 messages always carry the lock height.
+    let other = json.replace("\"lock_height\":0,", "");
+    assert_ne!(other, json);
+    assert!(serde_json::from_str::<VersionedSlate>(&other).is_err());
+
+    // Commitment of the wrong length.
+    let input = to_hex(&ali.message.input.0[..]);
+    let other = json.replace(&input, &input[2..]);
+    assert!(serde_json::from_str::<VersionedSlate>(&other).is_err());
+
+    // Not hex.
+    let other = json.replace(&input, &input.replace(&input[..2], "zz"));
+    assert!(serde_json::from_str::<VersionedSlate>(&other).is_err());
+
+    // Zero keys are only read as kernel offsets.
+    let offset = to_hex(&ali.message.kernel_offset.0[..]);
+    let zero = to_hex(&ZERO_KEY.0[..]);
+    let read = serde_json::from_str::<VersionedSlate>(&json.replace(&offset, &zero)).unwrap();
+    assert_eq!(read.into_slate().unwrap().message.kernel_offset, ZERO_KEY);
+    let bob = Receiver::respond(&secp, &ali.message).unwrap();
+    let slate = Slate { message: ali.message.clone(), response: Some(bob.response.clone()) };
+    let json = serde_json::to_string(&VersionedSlate::from(slate)).unwrap();
+    let sign = to_hex(&bob.response.sign.0[..]);
+    assert!(serde_json::from_str::<VersionedSlate>(&json.replace(&sign, &zero)).is_err());
+}
+
+#[test]
+fn test_slate_v1() {
+    use crate::{rand_blinding, Sender, Error};
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    // Version 1 slate, whose kernel offset is lost.
+    let ali = Sender::initiate(&secp, 40, &rand_blinding(&secp), 25, 2).unwrap();
+    let msg = ali.message;
+    let message = MessageV1 {
+        amount: msg.amount,
+        fee: msg.fee,
+        input: msg.input,
+        change_output: msg.change_output,
+        nonce: msg.nonce,
+        sum_of_bliding_factors: msg.sum_of_bliding_factors
+    };
+    let json = serde_json::to_string(
+        &VersionedSlate::V1(SlateV1 { message, response: None })
+    ).unwrap();
+    assert!(json.contains("\"version\":\"1\""));
+    assert!(!json.contains("kernel_offset"));
+
+    let read = serde_json::from_str::<VersionedSlate>(&json).unwrap();
+    assert_eq!(read.into_slate(), Err(Error::Ser(ser::Error::UnsupportedVersion(1))));
+}
+
+#[test]
+fn test_slate_v2() {
+    use crate::{rand_blinding, Sender, Receiver};
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    // Version 2 slate, upgraded to a message without a lock.
+    let ali = Sender::initiate(&secp, 40, &rand_blinding(&secp), 25, 2).unwrap();
+    let msg = ali.message.clone();
+    let message = MessageV2 {
+        amount: msg.amount,
+        fee: msg.fee,
+        input: msg.input,
+        change_output: msg.change_output,
+        nonce: msg.nonce,
+        sum_of_bliding_factors: msg.sum_of_bliding_factors,
+        kernel_offset: msg.kernel_offset
+    };
+    let json = serde_json::to_string(
+        &VersionedSlate::V2(SlateV2 { message, response: None })
+    ).unwrap();
+    assert!(json.contains("\"version\":\"2\""));
+    assert!(!json.contains("lock_height"));
+
+    let read = serde_json::from_str::<VersionedSlate>(&json).unwrap().into_slate().unwrap();
+    assert_eq!(read.message, ali.message);
+    let bob = Receiver::respond(&secp, &read.message).unwrap();
+    let tx = ali.finalize(&secp, &bob.response).unwrap();
+    assert_eq!(tx.validate(&secp), Ok(()));
+
+    // A locked message can't be passed off as version 2.
+    let ali = Sender::initiate(&secp, 40, &rand_blinding(&secp), 25, 2).unwrap()
+        .with_lock_height(10);
+    let slate = Slate { message: ali.message.clone(), response: None };
+    let json = serde_json::to_string(&VersionedSlate::from(slate)).unwrap();
+    let other = json.replace("\"version\":\"3\"", "\"version\":\"2\"");
+    assert!(serde_json::from_str::<VersionedSlate>(&other).is_err());
+}
+
+#[test]
+fn test_invoice_json() {
+    use crate::{rand_blinding, Invoice, Payee, Payer, Payment};
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    // Bob -> Alice.
+    let bob = Payee::invoice(&secp, 25).unwrap();
+    let json = serde_json::to_string(&bob.invoice).unwrap();
+    assert!(json.contains(&to_hex(&bob.invoice.nonce.0[..])));
+    let invoice: Invoice = serde_json::from_str(&json).unwrap();
+    assert_eq!(invoice, bob.invoice);
+
+    // Alice -> Bob.
+    let ali = Payer::pay(&secp, &invoice, 40, &rand_blinding(&secp), 2).unwrap();
+    let json = serde_json::to_string(&ali.payment).unwrap();
+    assert!(json.contains(&to_hex(&ali.payment.sign.0[..])));
+    let payment: Payment = serde_json::from_str(&json).unwrap();
+    assert_eq!(payment, ali.payment);
+
+    let tx = bob.finalize(&secp, &payment).unwrap();
+    assert_eq!(tx.validate(&secp), Ok(()));
+}
+
+#[test]
+fn test_hex() {
+    assert_eq!(to_hex(&[0, 1, 0xab, 0xff]), "0001abff");
+    assert_eq!(from_hex("0001abff"), Some(vec![0, 1, 0xab, 0xff]));
+    assert_eq!(from_hex("0001ABFF"), Some(vec![0, 1, 0xab, 0xff]));
+    assert_eq!(from_hex("abc"), None);
+    assert_eq!(from_hex("zz"), None);
+    assert_eq!(from_hex("+a+b"), None);
+}
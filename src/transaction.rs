@@ -0,0 +1,407 @@
+use secp256k1::{
+    Secp256k1, ContextFlag,
+    key::{SecretKey, ZERO_KEY},
+    pedersen::{Commitment, RangeProof},
+};
+use rand::thread_rng;
+
+use crate::{commit, offset_sum, range_proof, Error, Result};
+use crate::consensus;
+use crate::kernel::{KernelFeatures, TxKernel};
+use crate::ser::{self, Writeable, Readable, Writer, Reader};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OutputFeatures {
+    // Regular transaction output.
+    #[default]
+    Plain,
+    // Output minted by a block.
+    Coinbase
+}
+
+impl OutputFeatures {
+    pub fn as_u8(self) -> u8 {
+        match self {
+            OutputFeatures::Plain => 0,
+            OutputFeatures::Coinbase => 1
+        }
+    }
+
+    pub fn from_u8(b: u8) -> Option<OutputFeatures> {
+        match b {
+            0 => Some(OutputFeatures::Plain),
+            1 => Some(OutputFeatures::Coinbase),
+            _ => None
+        }
+    }
+}
+
+impl Writeable for OutputFeatures {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        writer.write_u8(self.as_u8());
+        Ok(())
+    }
+}
+
+impl Readable for OutputFeatures {
+    fn read(reader: &mut Reader) -> Result<OutputFeatures> {
+        OutputFeatures::from_u8(reader.read_u8()?)
+            .ok_or_else(|| ser::Error::CorruptedData.into())
+    }
+}
+
+// Output commitment together with the proof that its value isn't negative.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Output {
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub features: OutputFeatures,
+    #[cfg_attr(feature = "serde", serde(with = "crate::slate::commitment_hex"))]
+    pub commit: Commitment,
+    #[cfg_attr(feature = "serde", serde(with = "crate::slate::range_proof_hex"))]
+    pub proof: RangeProof
+}
+
+impl Output {
+    pub fn new(secp: &Secp256k1, value: u64, blinding: &SecretKey) -> Result<Output> {
+        Output::with_features(secp, OutputFeatures::Plain, value, blinding)
+    }
+
+    pub fn coinbase(secp: &Secp256k1, value: u64, blinding: &SecretKey) -> Result<Output> {
+        Output::with_features(secp, OutputFeatures::Coinbase, value, blinding)
+    }
+
+    pub fn with_features(
+        secp: &Secp256k1,
+        features: OutputFeatures,
+        value: u64,
+        blinding: &SecretKey
+    ) -> Result<Output> {
+        Ok(Output {
+            features,
+            commit: commit(secp, value, blinding)?,
+            proof: range_proof(secp, value, blinding)
+        })
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.features == OutputFeatures::Coinbase
+    }
+
+    pub fn verify(&self, secp: &Secp256k1) -> Result<()> {
+        secp.verify_bullet_proof(self.commit, self.proof, None)
+            .map(|_| ())
+            .map_err(|_| Error::InvalidRangeProof)
+    }
+}
+
+impl Writeable for Output {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        self.features.write(writer)?;
+        self.commit.write(writer)?;
+        self.proof.write(writer)
+    }
+}
+
+impl Readable for Output {
+    fn read(reader: &mut Reader) -> Result<Output> {
+        Ok(Output {
+            features: OutputFeatures::read(reader)?,
+            commit: Commitment::read(reader)?,
+            proof: RangeProof::read(reader)?
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction {
+    pub inputs: Vec<Commitment>,
+    pub outputs: Vec<Output>,
+    pub kernels: Vec<TxKernel>,
+    pub kernel_offset: SecretKey
+}
+
+impl Transaction {
+    // Merge transactions into one: offsets are summed, outputs spent by
+    // inputs of the same aggregate are cut through and everything is sorted,
+    // so the result doesn't tell which parts came from which transaction.
+    pub fn aggregate(secp: &Secp256k1, txs: Vec<Transaction>) -> Result<Transaction> {
+        if txs.is_empty() {
+            return Err(Error::EmptyTransaction);
+        }
+        let mut inputs = vec![];
+        let mut outputs = vec![];
+        let mut kernels = vec![];
+        let mut offsets = vec![];
+        for tx in txs {
+            inputs.extend(tx.inputs);
+            outputs.extend(tx.outputs);
+            kernels.extend(tx.kernels);
+            offsets.push(tx.kernel_offset);
+        }
+
+        // Cut-through.
+        let mut remaining_inputs = vec![];
+        for input in inputs {
+            match outputs.iter().position(|o| o.commit == input) {
+                Some(i) => { outputs.remove(i); },
+                None => remaining_inputs.push(input)
+            }
+        }
+
+        let mut tx = Transaction {
+            inputs: remaining_inputs,
+            outputs,
+            kernels,
+            kernel_offset: offset_sum(secp, offsets, vec![])?
+        };
+        tx.sort();
+        Ok(tx)
+    }
+
+    // Canonical order of inputs, outputs and kernels.
+    pub fn sort(&mut self) {
+        self.inputs.sort();
+        self.outputs.sort_by_key(|o| o.commit);
+        self.kernels.sort_by_key(|k| k.hash());
+    }
+
+    pub fn fee(&self) -> Result<u64> {
+        self.kernels.iter()
+            .try_fold(0u64, |sum, k| sum.checked_add(k.fee))
+            .ok_or(Error::FeeOverflow)
+    }
+
+    // Lowest height of a block the transaction can be included in.
+    pub fn lock_height(&self) -> u64 {
+        self.kernels.iter().map(|k| k.lock_height()).max().unwrap_or(0)
+    }
+
+    pub fn weight(&self) -> u64 {
+        consensus::weight(self.inputs.len(), self.outputs.len(), self.kernels.len())
+    }
+
+    // Kernel excess recomputed from the transaction:
+    // excess = outputs + fee * H - inputs - kernel_offset * G
+    pub fn kernel_excess(&self, secp: &Secp256k1) -> Result<Commitment> {
+        let mut positive: Vec<Commitment> = self.outputs.iter().map(|o| o.commit).collect();
+        let fee = self.fee()?;
+        if fee != 0 {
+            positive.push(secp.commit_value(fee)?);
+        }
+        let mut negative = self.inputs.clone();
+        if self.kernel_offset != ZERO_KEY {
+            negative.push(secp.commit(0, self.kernel_offset)?);
+        }
+        Ok(secp.commit_sum(positive, negative)?)
+    }
+
+    // Check the Mimblewimble balance equation. Kernel signatures only
+    // verify if the excesses have no H component, so together with the
+    // range proofs a valid transaction proves that inputs == outputs + fee.
+    // Coinbase outputs and kernels are only minted by blocks. Output
+    // features aren't signed, so this also keeps a relayer from turning
+    // plain outputs into coinbase ones, frozen until maturity.
+    pub fn validate(&self, secp: &Secp256k1) -> Result<()> {
+        if self.inputs.is_empty() || self.outputs.is_empty() || self.kernels.is_empty() {
+            return Err(Error::EmptyTransaction);
+        }
+        if self.outputs.iter().any(|o| o.is_coinbase())
+            || self.kernels.iter().any(|k| k.features == KernelFeatures::Coinbase)
+        {
+            return Err(Error::InvalidCoinbase);
+        }
+        for output in &self.outputs {
+            output.verify(secp)?;
+        }
+        let excesses = self.kernels.iter().map(|k| k.excess).collect();
+        if self.kernel_excess(secp)? != secp.commit_sum(excesses, vec![])? {
+            return Err(Error::UnbalancedTransaction);
+        }
+        for kernel in &self.kernels {
+            kernel.verify(secp)?;
+        }
+        Ok(())
+    }
+}
+
+impl Writeable for Transaction {
+    fn write(&self, writer: &mut Writer) -> Result<()> {
+        writer.write_vec(&self.inputs)?;
+        writer.write_vec(&self.outputs)?;
+        writer.write_vec(&self.kernels)?;
+        self.kernel_offset.write(writer)
+    }
+}
+
+impl Readable for Transaction {
+    fn read(reader: &mut Reader) -> Result<Transaction> {
+        Ok(Transaction {
+            inputs: reader.read_vec()?,
+            outputs: reader.read_vec()?,
+            kernels: reader.read_vec()?,
+            kernel_offset: reader.read_kernel_offset()?
+        })
+    }
+}
+
+// Transaction with a single signer spending `input` into `outputs`.
+#[cfg(test)]
+pub(crate) fn single_signer_tx(
+    secp: &Secp256k1,
+    input: (u64, SecretKey),
+    outputs: Vec<(u64, SecretKey)>,
+    fee: u64,
+    kernel_offset: SecretKey
+) -> Transaction {
+    let positive: Vec<SecretKey> = outputs.iter().map(|o| o.1).collect();
+    let mut negative = vec![input.1];
+    if kernel_offset != ZERO_KEY {
+        negative.push(kernel_offset);
+    }
+    let excess = secp.blind_sum(positive, negative).unwrap();
+
+    Transaction {
+        inputs: vec![commit(secp, input.0, &input.1).unwrap()],
+        outputs: outputs.iter().map(|o| Output::new(secp, o.0, &o.1).unwrap()).collect(),
+        kernels: vec![TxKernel::new(secp, KernelFeatures::Plain, fee, 0, &excess).unwrap()],
+        kernel_offset
+    }
+}
+
+#[test]
+fn test_validate() {
+    use crate::rand_blinding;
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    let input = (40, rand_blinding(&secp));
+    let outputs = vec![(15, rand_blinding(&secp)), (20, rand_blinding(&secp))];
+
+    // Balanced with fee and offset.
+    let offset = rand_blinding(&secp);
+    let tx = single_signer_tx(&secp, input, outputs.clone(), 5, offset);
+    assert_eq!(tx.validate(&secp), Ok(()));
+
+    // Balanced without offset.
+    let tx = single_signer_tx(&secp, input, outputs.clone(), 5, ZERO_KEY);
+    assert_eq!(tx.validate(&secp), Ok(()));
+
+    // Wrong fee.
+    let mut tx = single_signer_tx(&secp, input, outputs.clone(), 5, offset);
+    tx.kernels[0].fee = 4;
+    assert_eq!(tx.validate(&secp), Err(Error::UnbalancedTransaction));
+
+    // Wrong offset.
+    let mut tx = single_signer_tx(&secp, input, outputs.clone(), 5, offset);
+    tx.kernel_offset = rand_blinding(&secp);
+    assert_eq!(tx.validate(&secp), Err(Error::UnbalancedTransaction));
+
+    // Inflation: outputs worth more than the input.
+    let tx = single_signer_tx(&secp, input, outputs.clone(), 10, offset);
+    assert_eq!(tx.validate(&secp), Err(Error::UnbalancedTransaction));
+
+    // Forged signature.
+    let mut tx = single_signer_tx(&secp, input, outputs.clone(), 5, offset);
+    tx.kernels[0].excess_sig.partials_sum = rand_blinding(&secp);
+    assert_eq!(tx.validate(&secp), Err(Error::IncorrectSignature));
+
+    // Range proofs swapped between outputs.
+    let mut tx = single_signer_tx(&secp, input, outputs.clone(), 5, offset);
+    let proof = tx.outputs[0].proof;
+    tx.outputs[0].proof = tx.outputs[1].proof;
+    tx.outputs[1].proof = proof;
+    assert_eq!(tx.validate(&secp), Err(Error::InvalidRangeProof));
+
+    // Fees wrapping around to the right total.
+    let mut tx = single_signer_tx(&secp, input, outputs.clone(), 5, offset);
+    let mut kernel = tx.kernels[0].clone();
+    kernel.fee = u64::MAX;
+    tx.kernels.push(kernel);
+    tx.kernels[0].fee = 6;
+    assert_eq!(tx.fee(), Err(Error::FeeOverflow));
+    assert_eq!(tx.validate(&secp), Err(Error::FeeOverflow));
+
+    // Output turned into a coinbase one.
+    let mut tx = single_signer_tx(&secp, input, outputs.clone(), 5, offset);
+    tx.outputs[0].features = OutputFeatures::Coinbase;
+    assert_eq!(tx.validate(&secp), Err(Error::InvalidCoinbase));
+
+    // No outputs.
+    let mut tx = single_signer_tx(&secp, input, outputs, 5, offset);
+    tx.outputs.clear();
+    assert_eq!(tx.validate(&secp), Err(Error::EmptyTransaction));
+}
+
+#[test]
+fn test_aggregate() {
+    use crate::{rand_blinding, Sender, Receiver};
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+
+    // Alice pays Bob.
+    let ali = Sender::initiate(&secp, 40, &rand_blinding(&secp), 25, 2).unwrap();
+    let bob = Receiver::respond(&secp, &ali.message).unwrap();
+    let tx1 = ali.finalize(&secp, &bob.response).unwrap();
+
+    // Bob pays Carol out of the output he just received.
+    let bob_output = bob.response.output.commit;
+    let bob2 = Sender::initiate(&secp, 25, bob.output_blinding(), 20, 1).unwrap();
+    let carol = Receiver::respond(&secp, &bob2.message).unwrap();
+    let tx2 = bob2.finalize(&secp, &carol.response).unwrap();
+
+    // Unrelated transaction.
+    let tx3 = single_signer_tx(
+        &secp,
+        (30, rand_blinding(&secp)),
+        vec![(29, rand_blinding(&secp))],
+        1,
+        rand_blinding(&secp)
+    );
+
+    let tx = Transaction::aggregate(&secp, vec![tx1, tx2, tx3]).unwrap();
+    assert_eq!(tx.validate(&secp), Ok(()));
+    assert_eq!(tx.kernels.len(), 3);
+    assert_eq!(tx.fee(), Ok(4));
+    assert_eq!(tx.weight(), consensus::weight(2, 4, 3));
+
+    // Bob's output was cut through.
+    assert_eq!(tx.inputs.len(), 2);
+    assert_eq!(tx.outputs.len(), 4);
+    assert!(!tx.inputs.contains(&bob_output));
+    assert!(tx.outputs.iter().all(|o| o.commit != bob_output));
+
+    // Canonical order.
+    let mut sorted = tx.clone();
+    sorted.sort();
+    assert_eq!(sorted, tx);
+
+    // Aggregates aggregate further.
+    let tx4 = single_signer_tx(
+        &secp,
+        (10, rand_blinding(&secp)),
+        vec![(9, rand_blinding(&secp))],
+        1,
+        rand_blinding(&secp)
+    );
+    let tx = Transaction::aggregate(&secp, vec![tx, tx4]).unwrap();
+    assert_eq!(tx.validate(&secp), Ok(()));
+    assert_eq!(tx.kernels.len(), 4);
+
+    // Transactions without an offset aggregate to none.
+    let zero_offset = || single_signer_tx(
+        &secp,
+        (10, rand_blinding(&secp)),
+        vec![(9, rand_blinding(&secp))],
+        1,
+        ZERO_KEY
+    );
+    let tx = Transaction::aggregate(&secp, vec![zero_offset(), zero_offset()]).unwrap();
+    assert_eq!(tx.kernel_offset, ZERO_KEY);
+    assert_eq!(tx.validate(&secp), Ok(()));
+
+    assert_eq!(Transaction::aggregate(&secp, vec![]), Err(Error::EmptyTransaction));
+}
@@ -0,0 +1,256 @@
+use std::collections::{HashMap, HashSet};
+
+use secp256k1::{
+    Secp256k1, ContextFlag,
+    pedersen::Commitment,
+};
+use rand::thread_rng;
+
+use crate::{rand_blinding, Error, Result};
+use crate::block::Block;
+use crate::consensus::ChainParams;
+use crate::transaction::{Output, OutputFeatures, Transaction};
+
+// How and at which height an unspent output was created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputInfo {
+    pub features: OutputFeatures,
+    pub height: u64
+}
+
+// Changes made to the set by one block or transaction, to roll it back.
+#[derive(Debug, Clone, PartialEq)]
+struct Undo {
+    spent: Vec<(Commitment, OutputInfo)>,
+    created: Vec<Commitment>
+}
+
+// Unspent outputs of the chain.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UtxoSet {
+    outputs: HashMap<Commitment, OutputInfo>,
+    history: Vec<Undo>
+}
+
+impl UtxoSet {
+    pub fn new() -> UtxoSet {
+        UtxoSet::default()
+    }
+
+    pub fn get(&self, commit: &Commitment) -> Option<&OutputInfo> {
+        self.outputs.get(commit)
+    }
+
+    pub fn contains(&self, commit: &Commitment) -> bool {
+        self.outputs.contains_key(commit)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Commitment, &OutputInfo)> {
+        self.outputs.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.outputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+
+    // Inputs included at `height` may only spend coinbase outputs that
+    // matured by then.
+    pub fn check_maturity(
+        &self,
+        params: &ChainParams,
+        inputs: &[Commitment],
+        height: u64
+    ) -> Result<()> {
+        for input in inputs {
+            if let Some(info) = self.outputs.get(input) {
+                if info.features == OutputFeatures::Coinbase
+                    && height < params.coinbase_spendable_at(info.height)
+                {
+                    return Err(Error::ImmatureCoinbase);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Every input spends an unspent output at most once and no output
+    // already exists.
+    fn check_spend(&self, inputs: &[Commitment], outputs: &[Output]) -> Result<()> {
+        let mut spent = HashSet::new();
+        for input in inputs {
+            if !self.outputs.contains_key(input) || !spent.insert(input) {
+                return Err(Error::MissingInput);
+            }
+        }
+        let mut created = HashSet::new();
+        for output in outputs {
+            if self.outputs.contains_key(&output.commit) || !created.insert(output.commit) {
+                return Err(Error::DuplicateOutput);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate_block(&self, params: &ChainParams, block: &Block) -> Result<()> {
+        self.check_spend(&block.body.inputs, &block.body.outputs)?;
+        self.check_maturity(params, &block.body.inputs, block.header.height)
+    }
+
+    // Check a transaction to be included in the block at `height`.
+    pub fn validate_tx(&self, params: &ChainParams, tx: &Transaction, height: u64) -> Result<()> {
+        self.check_spend(&tx.inputs, &tx.outputs)?;
+        self.check_maturity(params, &tx.inputs, height)
+    }
+
+    pub fn apply_block(&mut self, params: &ChainParams, block: &Block) -> Result<()> {
+        self.validate_block(params, block)?;
+        self.apply(&block.body.inputs, &block.body.outputs, block.header.height);
+        Ok(())
+    }
+
+    // Apply a transaction as if it was included in the block at `height`.
+    pub fn apply_tx(&mut self, params: &ChainParams, tx: &Transaction, height: u64) -> Result<()> {
+        self.validate_tx(params, tx, height)?;
+        self.apply(&tx.inputs, &tx.outputs, height);
+        Ok(())
+    }
+
+    fn apply(&mut self, inputs: &[Commitment], outputs: &[Output], height: u64) {
+        let mut undo = Undo { spent: vec![], created: vec![] };
+        for input in inputs {
+            if let Some(info) = self.outputs.remove(input) {
+                undo.spent.push((*input, info));
+            }
+        }
+        for output in outputs {
+            let info = OutputInfo { features: output.features, height };
+            self.outputs.insert(output.commit, info);
+            undo.created.push(output.commit);
+        }
+        self.history.push(undo);
+    }
+
+    // Undo the last applied block or transaction.
+    pub fn rollback(&mut self) -> Result<()> {
+        let undo = self.history.pop().ok_or(Error::NothingToRollback)?;
+        for commit in undo.created {
+            self.outputs.remove(&commit);
+        }
+        self.outputs.extend(undo.spent);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_coinbase_maturity() {
+    use crate::block::transfer;
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+    let params = ChainParams { coinbase_maturity: 3, ..ChainParams::default() };
+    let mut utxos = UtxoSet::new();
+
+    let genesis_blinding = rand_blinding(&secp);
+    let genesis = Block::genesis(&secp, &params, &genesis_blinding, 0).unwrap();
+    utxos.apply_block(&params, &genesis).unwrap();
+    let coinbase = genesis.body.outputs[0].commit;
+    assert_eq!(
+        utxos.get(&coinbase),
+        Some(&OutputInfo { features: OutputFeatures::Coinbase, height: 0 })
+    );
+
+    let tx = transfer(&secp, params.reward(0), &genesis_blinding, 1_000, 2);
+    assert_eq!(utxos.validate_tx(&params, &tx, 2), Err(Error::ImmatureCoinbase));
+    assert_eq!(utxos.validate_tx(&params, &tx, 3), Ok(()));
+
+    // Too early in a block.
+    let mut prev = genesis.header;
+    let block = Block::new(&secp, &params, &prev, vec![tx.clone()], &rand_blinding(&secp), 1)
+        .unwrap();
+    assert_eq!(utxos.validate_block(&params, &block), Err(Error::ImmatureCoinbase));
+
+    for timestamp in 1..3 {
+        let block = Block::new(&secp, &params, &prev, vec![], &rand_blinding(&secp), timestamp)
+            .unwrap();
+        assert_eq!(utxos.apply_block(&params, &block), Ok(()));
+        prev = block.header;
+    }
+
+    // Matured at height 3.
+    let block = Block::new(&secp, &params, &prev, vec![tx.clone()], &rand_blinding(&secp), 3)
+        .unwrap();
+    assert_eq!(utxos.apply_block(&params, &block), Ok(()));
+    assert_eq!(utxos.get(&coinbase), None);
+
+    // Regular outputs can be spent right away.
+    let received = tx.outputs.iter()
+        .find(|o| utxos.get(&o.commit).map(|i| i.height) == Some(3))
+        .unwrap();
+    assert_eq!(utxos.get(&received.commit).unwrap().features, OutputFeatures::Plain);
+    assert_eq!(utxos.check_maturity(&params, &[received.commit], 4), Ok(()));
+}
+
+#[test]
+fn test_utxo_set() {
+    use crate::block::transfer;
+
+    let mut secp = Secp256k1::with_caps(ContextFlag::Commit);
+    secp.randomize(&mut thread_rng());
+    let params = ChainParams { coinbase_maturity: 0, ..ChainParams::default() };
+    let mut utxos = UtxoSet::new();
+
+    let genesis_blinding = rand_blinding(&secp);
+    let genesis = Block::genesis(&secp, &params, &genesis_blinding, 0).unwrap();
+    utxos.apply_block(&params, &genesis).unwrap();
+    let coinbase = genesis.body.outputs[0].commit;
+
+    // Input not in the set.
+    let unknown = transfer(&secp, 5_000, &rand_blinding(&secp), 2_000, 4);
+    assert_eq!(utxos.validate_tx(&params, &unknown, 1), Err(Error::MissingInput));
+    let prev = &genesis.header;
+    let block = Block::new(&secp, &params, prev, vec![unknown], &rand_blinding(&secp), 1).unwrap();
+    assert_eq!(utxos.apply_block(&params, &block), Err(Error::MissingInput));
+    assert_eq!(utxos.len(), 1);
+
+    // Spend the coinbase.
+    let tx = transfer(&secp, params.reward(0), &genesis_blinding, 1_000, 2);
+    assert_eq!(utxos.apply_tx(&params, &tx, 1), Ok(()));
+    assert!(!utxos.contains(&coinbase));
+    assert_eq!(utxos.len(), 2);
+    for output in &tx.outputs {
+        assert_eq!(utxos.get(&output.commit).unwrap().height, 1);
+    }
+
+    // Already spent, and outputs already there.
+    assert_eq!(utxos.apply_tx(&params, &tx, 1), Err(Error::MissingInput));
+    let mut dup = tx.clone();
+    dup.inputs = vec![tx.outputs[0].commit];
+    assert_eq!(utxos.validate_tx(&params, &dup, 1), Err(Error::DuplicateOutput));
+
+    // Double spend inside one transaction.
+    let mut dup = tx.clone();
+    dup.inputs = vec![tx.outputs[0].commit, tx.outputs[0].commit];
+    assert_eq!(utxos.validate_tx(&params, &dup, 1), Err(Error::MissingInput));
+
+    // Rolling back restores the coinbase.
+    assert_eq!(utxos.rollback(), Ok(()));
+    assert!(utxos.contains(&coinbase));
+    assert_eq!(utxos.len(), 1);
+    assert_eq!(utxos.rollback(), Ok(()));
+    assert!(utxos.is_empty());
+    assert_eq!(utxos.rollback(), Err(Error::NothingToRollback));
+
+    // And blocks apply the same as transactions.
+    utxos.apply_block(&params, &genesis).unwrap();
+    let txs = vec![tx.clone()];
+    let block = Block::new(&secp, &params, prev, txs, &rand_blinding(&secp), 1).unwrap();
+    assert_eq!(utxos.apply_block(&params, &block), Ok(()));
+    assert_eq!(utxos.len(), 3);
+    for output in &tx.outputs {
+        assert!(utxos.contains(&output.commit));
+    }
+}